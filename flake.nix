@@ -62,7 +62,6 @@
           buildInputs = with pkgs; [
             rustup
             just
-            python3
           ];
         };
       }
@@ -89,6 +88,12 @@
               description = "Directory to store census data and serve static site";
             };
 
+            network = mkOption {
+              type = types.enum [ "mainnet" "testnet3" "testnet4" "signet" "regtest" ];
+              default = "mainnet";
+              description = "Bitcoin network to run the census of";
+            };
+
             seedNode = {
               address = mkOption {
                 type = types.str;
@@ -97,9 +102,9 @@
               };
 
               port = mkOption {
-                type = types.port;
-                default = 8333;
-                description = "Bitcoin seed node port";
+                type = types.nullOr types.port;
+                default = null;
+                description = "Bitcoin seed node port, the default port of the network if null";
               };
             };
 
@@ -213,11 +218,13 @@
                 # Run census and append to census.jsonl.
                 echo "Running census..."
                 ${cfg.package}/bin/bitcoin-node-census run \
-                  --address "${cfg.seedNode.address}" \
-                  --port ${toString cfg.seedNode.port} \
+                  --network ${cfg.network} \
+                  --seed "${cfg.seedNode.address}" \
+                  ${optionalString (cfg.seedNode.port != null) "--port ${toString cfg.seedNode.port}"} \
                   --concurrent ${toString cfg.concurrent} \
                   --format jsonl \
-                  >> census.jsonl
+                  --output census.jsonl \
+                  --append
 
                 echo "Census complete!"
               '' + optionalString cfg.backup.enable ''
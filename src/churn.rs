@@ -0,0 +1,500 @@
+use crate::diff::DiffFormat;
+use crate::node::{NetworkType, NodeObservation};
+use crate::report::date;
+use crate::stats::{median, service_flag_names};
+use bitcoin::p2p::ServiceFlags;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{self, Write};
+
+/// Churn counts of listening nodes between the previous and latest crawl.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ChurnCounts {
+    pub previous_nodes: usize,
+    pub latest_nodes: usize,
+    /// Nodes listening in the latest crawl but not the previous one.
+    pub new_nodes: usize,
+    /// Nodes listening in the previous crawl but not the latest one.
+    pub gone_nodes: usize,
+    /// Nodes listening in both crawls which advertised different service flags.
+    pub changed_services: usize,
+}
+
+/// Nodes listening in both crawls with known service flags, by whether they support a feature.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct FeatureTransitions {
+    pub feature: String,
+    /// Connection type of the nodes, `all` for the whole network.
+    pub connection_type: String,
+    /// Nodes which turned the feature on.
+    pub enabled: usize,
+    /// Nodes which turned the feature off.
+    pub disabled: usize,
+    pub stayed_enabled: usize,
+    pub stayed_disabled: usize,
+}
+
+/// Features of the transition matrix, as the service flags a node needs for them.
+fn features() -> [(&'static str, ServiceFlags); 3] {
+    [
+        ("v2_transport", ServiceFlags::P2P_V2),
+        ("compact_filters", ServiceFlags::COMPACT_FILTERS),
+        (
+            "v2_and_filters",
+            ServiceFlags::P2P_V2 | ServiceFlags::COMPACT_FILTERS,
+        ),
+    ]
+}
+
+/// Churn of listening nodes across crawls, nodes are identified by address and port.
+#[derive(Debug, Clone, Serialize)]
+pub struct NodeChurn {
+    pub previous_timestamp: u64,
+    pub latest_timestamp: u64,
+    /// Number of crawls the lifetimes are measured over.
+    pub crawls: usize,
+    #[serde(flatten)]
+    pub total: ChurnCounts,
+    /// Counts by connection type, new nodes by their latest and gone nodes by their previous one.
+    pub connection_types: BTreeMap<String, ChurnCounts>,
+    /// Nodes in both crawls which turned on each service flag.
+    pub flags_enabled: BTreeMap<String, usize>,
+    /// Nodes in both crawls which turned off each service flag.
+    pub flags_disabled: BTreeMap<String, usize>,
+    /// Transitions of every feature, overall and per connection type.
+    pub transitions: Vec<FeatureTransitions>,
+    /// Median time between the first and last crawl each node was listening in, the lower one
+    /// for an even number of nodes. Nodes listening in a single crawl are left out, their
+    /// lifetime is unknown rather than zero.
+    pub median_lifetime_seconds: u64,
+    /// Nodes listening in a single crawl, left out of the median lifetime.
+    pub single_crawl_nodes: usize,
+}
+
+/// Listening nodes of a crawl by address and port.
+type Crawl<'a> = HashMap<(&'a str, u16), &'a NodeObservation>;
+
+/// When a crawl was taken, its last observation.
+fn crawl_timestamp(observations: &[NodeObservation]) -> u64 {
+    observations.iter().map(|o| o.timestamp).max().unwrap_or(0)
+}
+
+impl NodeChurn {
+    /// Compute the churn between the two latest of the crawls, and node lifetimes over all of
+    /// them. Crawls are given as their node observations, in any order.
+    ///
+    /// A node missing from the previous crawl counts as new, even if an earlier crawl saw it.
+    /// Nodes with unknown service flags in either crawl never count as changed. Returns none
+    /// with fewer than two crawls.
+    pub fn new(crawls: &[Vec<NodeObservation>]) -> Option<Self> {
+        let mut crawls: Vec<(u64, Crawl)> = crawls
+            .iter()
+            .map(|observations| {
+                let nodes = observations
+                    .iter()
+                    .filter(|o| o.listening)
+                    .map(|o| ((o.address.as_str(), o.port), o))
+                    .collect();
+                (crawl_timestamp(observations), nodes)
+            })
+            .collect();
+        crawls.sort_by_key(|(timestamp, _)| *timestamp);
+        let [.., (previous_timestamp, previous), (latest_timestamp, latest)] = crawls.as_slice()
+        else {
+            return None;
+        };
+
+        let mut total = ChurnCounts::default();
+        let mut connection_types: BTreeMap<String, ChurnCounts> = NetworkType::ALL
+            .into_iter()
+            .map(|network| (network.to_string(), ChurnCounts::default()))
+            .collect();
+        let mut counts = |network: NetworkType, count: fn(&mut ChurnCounts)| {
+            count(&mut total);
+            if let Some(counts) = connection_types.get_mut(&network.to_string()) {
+                count(counts);
+            }
+        };
+
+        let mut flags_enabled = BTreeMap::new();
+        let mut flags_disabled = BTreeMap::new();
+        let mut transitions: HashMap<(&str, Option<NetworkType>), FeatureTransitions> =
+            HashMap::new();
+        for (key, node) in previous {
+            counts(node.network, |counts| counts.previous_nodes += 1);
+            let Some(latest) = latest.get(key) else {
+                counts(node.network, |counts| counts.gone_nodes += 1);
+                continue;
+            };
+            let (Some(before), Some(after)) = (node.services, latest.services) else {
+                continue;
+            };
+            if before != after {
+                counts(latest.network, |counts| counts.changed_services += 1);
+            }
+            for name in service_flag_names(ServiceFlags::from(after & !before)) {
+                *flags_enabled.entry(name).or_insert(0) += 1;
+            }
+            for name in service_flag_names(ServiceFlags::from(before & !after)) {
+                *flags_disabled.entry(name).or_insert(0) += 1;
+            }
+            for (feature, flags) in features() {
+                let was = ServiceFlags::from(before).has(flags);
+                let is = ServiceFlags::from(after).has(flags);
+                for scope in [None, Some(latest.network)] {
+                    let transition = transitions.entry((feature, scope)).or_default();
+                    match (was, is) {
+                        (false, true) => transition.enabled += 1,
+                        (true, false) => transition.disabled += 1,
+                        (true, true) => transition.stayed_enabled += 1,
+                        (false, false) => transition.stayed_disabled += 1,
+                    }
+                }
+            }
+        }
+        for (key, node) in latest {
+            counts(node.network, |counts| counts.latest_nodes += 1);
+            if !previous.contains_key(key) {
+                counts(node.network, |counts| counts.new_nodes += 1);
+            }
+        }
+
+        let transitions = features()
+            .into_iter()
+            .flat_map(|(feature, _)| {
+                std::iter::once(None)
+                    .chain(NetworkType::ALL.map(Some))
+                    .map(move |scope| (feature, scope))
+            })
+            .map(|(feature, scope)| FeatureTransitions {
+                feature: feature.to_string(),
+                connection_type: scope
+                    .map_or_else(|| "all".to_string(), |network| network.to_string()),
+                ..transitions.remove(&(feature, scope)).unwrap_or_default()
+            })
+            .collect();
+
+        // First and last timestamp and number of crawls each node was listening in.
+        let mut seen: HashMap<(&str, u16), (u64, u64, usize)> = HashMap::new();
+        for (timestamp, nodes) in &crawls {
+            for key in nodes.keys() {
+                let (_, last, count) = seen.entry(*key).or_insert((*timestamp, *timestamp, 0));
+                *last = *timestamp;
+                *count += 1;
+            }
+        }
+        let single_crawl_nodes = seen.values().filter(|(_, _, count)| *count == 1).count();
+        let median_lifetime_seconds = median(
+            seen.values()
+                .filter(|(_, _, count)| *count > 1)
+                .map(|(first, last, _)| last - first)
+                .collect(),
+        );
+
+        Some(Self {
+            previous_timestamp: *previous_timestamp,
+            latest_timestamp: *latest_timestamp,
+            crawls: crawls.len(),
+            total,
+            connection_types,
+            flags_enabled,
+            flags_disabled,
+            transitions,
+            median_lifetime_seconds,
+            single_crawl_nodes,
+        })
+    }
+
+    pub fn format(&self, format: DiffFormat) -> Result<String, Box<dyn std::error::Error>> {
+        Ok(match format {
+            DiffFormat::Table => self.to_string(),
+            DiffFormat::Json => format!("{}\n", serde_json::to_string_pretty(self)?),
+            DiffFormat::Markdown => self.format_markdown()?,
+        })
+    }
+
+    /// Rows of the counts, overall and per connection type in report order.
+    fn rows(&self) -> impl Iterator<Item = (String, &ChurnCounts)> {
+        std::iter::once(("all".to_string(), &self.total)).chain(
+            NetworkType::ALL.into_iter().filter_map(|network| {
+                let name = network.to_string();
+                let counts = self.connection_types.get(&name)?;
+                Some((name, counts))
+            }),
+        )
+    }
+
+    fn format_markdown(&self) -> Result<String, fmt::Error> {
+        let mut out = String::new();
+        writeln!(
+            out,
+            "Node churn from {} to {}, median lifetime {:.1} days over {} crawls, excluding {} \
+             nodes listening in a single crawl.\n",
+            date(self.previous_timestamp),
+            date(self.latest_timestamp),
+            days(self.median_lifetime_seconds),
+            self.crawls,
+            self.single_crawl_nodes
+        )?;
+        writeln!(
+            out,
+            "| Connection type | Previous | Latest | New | Gone | Changed services |"
+        )?;
+        writeln!(out, "|---|--:|--:|--:|--:|--:|")?;
+        for (name, counts) in self.rows() {
+            writeln!(
+                out,
+                "| {name} | {} | {} | {} | {} | {} |",
+                counts.previous_nodes,
+                counts.latest_nodes,
+                counts.new_nodes,
+                counts.gone_nodes,
+                counts.changed_services
+            )?;
+        }
+        writeln!(out, "\n| Service flag | Enabled | Disabled |")?;
+        writeln!(out, "|---|--:|--:|")?;
+        for (flag, enabled, disabled) in self.flag_changes() {
+            writeln!(out, "| {flag} | {enabled} | {disabled} |")?;
+        }
+        writeln!(
+            out,
+            "\n| Feature | Connection type | Enabled | Disabled | Stayed enabled | Stayed disabled |"
+        )?;
+        writeln!(out, "|---|---|--:|--:|--:|--:|")?;
+        for transition in &self.transitions {
+            writeln!(
+                out,
+                "| {} | {} | {} | {} | {} | {} |",
+                transition.feature,
+                transition.connection_type,
+                transition.enabled,
+                transition.disabled,
+                transition.stayed_enabled,
+                transition.stayed_disabled
+            )?;
+        }
+        Ok(out)
+    }
+
+    /// Every changed service flag with the nodes turning it on and off.
+    fn flag_changes(&self) -> Vec<(&str, usize, usize)> {
+        let mut flags: Vec<&String> = self
+            .flags_enabled
+            .keys()
+            .chain(self.flags_disabled.keys())
+            .collect();
+        flags.sort();
+        flags.dedup();
+        flags
+            .into_iter()
+            .map(|flag| {
+                (
+                    flag.as_str(),
+                    self.flags_enabled.get(flag).copied().unwrap_or(0),
+                    self.flags_disabled.get(flag).copied().unwrap_or(0),
+                )
+            })
+            .collect()
+    }
+}
+
+/// Seconds in days.
+fn days(seconds: u64) -> f64 {
+    seconds as f64 / (24.0 * 60.0 * 60.0)
+}
+
+impl fmt::Display for NodeChurn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<15} {:>8} {:>8} {:>8} {:>8} {:>16}",
+            "connection_type", "previous", "latest", "new", "gone", "changed_services"
+        )?;
+        for (name, counts) in self.rows() {
+            writeln!(
+                f,
+                "{name:<15} {:>8} {:>8} {:>8} {:>8} {:>16}",
+                counts.previous_nodes,
+                counts.latest_nodes,
+                counts.new_nodes,
+                counts.gone_nodes,
+                counts.changed_services
+            )?;
+        }
+        writeln!(f)?;
+        writeln!(
+            f,
+            "{:<15} {:>8} {:>8}",
+            "service_flag", "enabled", "disabled"
+        )?;
+        for (flag, enabled, disabled) in self.flag_changes() {
+            writeln!(f, "{flag:<15} {enabled:>8} {disabled:>8}")?;
+        }
+        writeln!(f)?;
+        writeln!(
+            f,
+            "{:<15} {:<15} {:>8} {:>8} {:>14} {:>15}",
+            "feature",
+            "connection_type",
+            "enabled",
+            "disabled",
+            "stayed_enabled",
+            "stayed_disabled"
+        )?;
+        for transition in &self.transitions {
+            writeln!(
+                f,
+                "{:<15} {:<15} {:>8} {:>8} {:>14} {:>15}",
+                transition.feature,
+                transition.connection_type,
+                transition.enabled,
+                transition.disabled,
+                transition.stayed_enabled,
+                transition.stayed_disabled
+            )?;
+        }
+        writeln!(f)?;
+        writeln!(
+            f,
+            "median lifetime: {:.1} days over {} crawls, excluding {} nodes listening in a single \
+             crawl",
+            days(self.median_lifetime_seconds),
+            self.crawls,
+            self.single_crawl_nodes
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: u64 = 24 * 60 * 60;
+
+    fn node(address: &str, network: NetworkType, services: ServiceFlags) -> NodeObservation {
+        NodeObservation {
+            address: address.to_string(),
+            port: 8333,
+            network,
+            services: Some(services.to_u64()),
+            version: None,
+            listening: true,
+            timestamp: 0,
+        }
+    }
+
+    fn crawl(timestamp: u64, nodes: Vec<NodeObservation>) -> Vec<NodeObservation> {
+        nodes
+            .into_iter()
+            .map(|node| NodeObservation { timestamp, ..node })
+            .collect()
+    }
+
+    /// Three daily crawls, given out of order.
+    fn crawls() -> Vec<Vec<NodeObservation>> {
+        let ipv4 = NetworkType::Ipv4;
+        let ipv6 = NetworkType::Ipv6;
+        let v2 = ServiceFlags::NETWORK | ServiceFlags::P2P_V2;
+        let filters = ServiceFlags::NETWORK | ServiceFlags::COMPACT_FILTERS;
+        let mut gone = node("9.9.9.9", ipv4, ServiceFlags::NETWORK);
+        gone.listening = false;
+        vec![
+            crawl(
+                2 * DAY,
+                vec![
+                    node("1.1.1.1", ipv4, v2),
+                    node("::3", ipv6, filters),
+                    node("4.4.4.4", ipv4, ServiceFlags::NETWORK),
+                    gone,
+                ],
+            ),
+            crawl(
+                0,
+                vec![
+                    node("1.1.1.1", ipv4, ServiceFlags::NETWORK),
+                    node("2.2.2.2", ipv4, v2),
+                ],
+            ),
+            crawl(
+                DAY,
+                vec![
+                    node("1.1.1.1", ipv4, v2),
+                    node("2.2.2.2", ipv4, ServiceFlags::NETWORK),
+                    node("::3", ipv6, ServiceFlags::NETWORK),
+                ],
+            ),
+        ]
+    }
+
+    #[test]
+    fn churn_between_latest_crawls() {
+        let churn = NodeChurn::new(&crawls()).unwrap();
+        assert_eq!(churn.previous_timestamp, DAY);
+        assert_eq!(churn.latest_timestamp, 2 * DAY);
+        assert_eq!(churn.crawls, 3);
+
+        let total = &churn.total;
+        assert_eq!(
+            [
+                total.previous_nodes,
+                total.latest_nodes,
+                total.new_nodes,
+                total.gone_nodes,
+                total.changed_services
+            ],
+            [3, 3, 1, 1, 1]
+        );
+        let ipv4 = &churn.connection_types["ipv4"];
+        assert_eq!(
+            (ipv4.new_nodes, ipv4.gone_nodes, ipv4.changed_services),
+            (1, 1, 0)
+        );
+        assert_eq!(churn.connection_types["ipv6"].changed_services, 1);
+
+        assert_eq!(
+            churn.flags_enabled,
+            BTreeMap::from([("compact_filters".to_string(), 1)])
+        );
+        assert!(churn.flags_disabled.is_empty());
+    }
+
+    #[test]
+    fn lifetimes() {
+        let churn = NodeChurn::new(&crawls()).unwrap();
+        // 1.1.1.1 listened for two days, 2.2.2.2 and ::3 for one, 4.4.4.4 only once.
+        assert_eq!(churn.median_lifetime_seconds, DAY);
+        assert_eq!(churn.single_crawl_nodes, 1);
+    }
+
+    #[test]
+    fn needs_two_crawls() {
+        assert!(NodeChurn::new(&crawls()[..1]).is_none());
+        assert!(NodeChurn::new(&[]).is_none());
+    }
+
+    fn transition(churn: &NodeChurn, feature: &str, connection_type: &str) -> [usize; 4] {
+        let transition = churn
+            .transitions
+            .iter()
+            .find(|t| t.feature == feature && t.connection_type == connection_type)
+            .unwrap();
+        [
+            transition.enabled,
+            transition.disabled,
+            transition.stayed_enabled,
+            transition.stayed_disabled,
+        ]
+    }
+
+    #[test]
+    fn feature_transitions() {
+        let churn = NodeChurn::new(&crawls()).unwrap();
+        assert_eq!(transition(&churn, "v2_transport", "all"), [0, 0, 1, 1]);
+        assert_eq!(transition(&churn, "compact_filters", "all"), [1, 0, 0, 1]);
+        assert_eq!(transition(&churn, "compact_filters", "ipv6"), [1, 0, 0, 0]);
+        assert_eq!(transition(&churn, "compact_filters", "ipv4"), [0, 0, 0, 1]);
+        assert_eq!(transition(&churn, "v2_and_filters", "tor_v3"), [0, 0, 0, 0]);
+        assert_eq!(churn.transitions.len(), 3 * (NetworkType::ALL.len() + 1));
+    }
+}
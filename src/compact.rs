@@ -0,0 +1,278 @@
+use crate::node::NetworkType;
+use crate::report::CensusReport;
+use crate::stats::{median, ConnectionTypeFeatures, FeatureStats};
+use chrono::Datelike;
+use clap::ValueEnum;
+use std::collections::{BTreeMap, BTreeSet};
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Length of the periods a history is downsampled to, in UTC.
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum Period {
+    Day,
+    /// Weeks start on Monday.
+    Week,
+    Month,
+}
+
+impl Period {
+    /// Index of the period containing a timestamp.
+    fn index(self, timestamp: u64) -> i64 {
+        let days = (timestamp / SECONDS_PER_DAY) as i64;
+        match self {
+            Period::Day => days,
+            // The Unix epoch was a Thursday.
+            Period::Week => (days + 3).div_euclid(7),
+            Period::Month => chrono::DateTime::from_timestamp(timestamp as i64, 0)
+                .map(|time| i64::from(time.year()) * 12 + i64::from(time.month0()))
+                .unwrap_or(0),
+        }
+    }
+}
+
+/// How the reports of a period are combined into one.
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum Aggregate {
+    /// Keep the last report of the period.
+    Last,
+    /// Take the median of every count across the period's reports.
+    Median,
+}
+
+/// Policy for compacting a history of reports.
+#[derive(Debug, Clone, Copy)]
+pub struct Compaction {
+    /// Period reports are downsampled to.
+    pub period: Period,
+    /// How the reports of a period are combined.
+    pub aggregate: Aggregate,
+    /// Reports younger than this many seconds are kept as is.
+    pub keep_raw_seconds: Option<u64>,
+    /// Reports older than this many seconds are dropped.
+    pub max_age_seconds: Option<u64>,
+}
+
+impl Compaction {
+    /// Compact a history as of a time, returning the reports sorted by timestamp.
+    ///
+    /// Reports of different networks are downsampled separately. Partial reports past the raw
+    /// window are dropped rather than downsampled.
+    pub fn apply(&self, mut reports: Vec<CensusReport>, now: u64) -> Vec<CensusReport> {
+        reports.sort_by_key(|report| report.timestamp);
+        if let Some(max_age) = self.max_age_seconds {
+            reports.retain(|report| report.timestamp >= now.saturating_sub(max_age));
+        }
+
+        let raw_since = self
+            .keep_raw_seconds
+            .map_or(u64::MAX, |keep_raw| now.saturating_sub(keep_raw));
+        let (raw, old): (Vec<_>, Vec<_>) = reports
+            .into_iter()
+            .partition(|report| report.timestamp >= raw_since);
+
+        let mut periods: BTreeMap<(i64, String), Vec<CensusReport>> = BTreeMap::new();
+        for report in old.into_iter().filter(|report| !report.partial) {
+            periods
+                .entry((
+                    self.period.index(report.timestamp),
+                    report.network.to_string(),
+                ))
+                .or_default()
+                .push(report);
+        }
+
+        let mut compacted: Vec<CensusReport> = periods
+            .into_values()
+            .filter_map(|reports| match self.aggregate {
+                Aggregate::Last => reports.into_iter().next_back(),
+                Aggregate::Median => median_report(&reports),
+            })
+            .chain(raw)
+            .collect();
+        compacted.sort_by_key(|report| report.timestamp);
+
+        compacted
+    }
+}
+
+/// Median of every key of count maps, a key missing from a map counts as zero.
+fn median_map<'a>(
+    maps: impl Iterator<Item = &'a BTreeMap<String, usize>> + Clone,
+) -> BTreeMap<String, usize> {
+    let keys: BTreeSet<&String> = maps.clone().flat_map(|map| map.keys()).collect();
+    keys.into_iter()
+        .map(|key| {
+            let counts = maps
+                .clone()
+                .map(|map| map.get(key).copied().unwrap_or(0))
+                .collect();
+            (key.clone(), median(counts))
+        })
+        .collect()
+}
+
+fn median_connection_type(features: &[&ConnectionTypeFeatures]) -> ConnectionTypeFeatures {
+    let field = |count: fn(&ConnectionTypeFeatures) -> usize| {
+        median(features.iter().map(|features| count(features)).collect())
+    };
+    ConnectionTypeFeatures {
+        total_nodes: field(|features| features.total_nodes),
+        v2_transport: field(|features| features.v2_transport),
+        compact_filters: field(|features| features.compact_filters),
+        v2_and_filters: field(|features| features.v2_and_filters),
+        service_flags: median_map(features.iter().map(|features| &features.service_flags)),
+        combinations: median_map(features.iter().map(|features| &features.combinations)),
+        protocol_versions: median_map(features.iter().map(|features| &features.protocol_versions)),
+        confidence_intervals: BTreeMap::new(),
+    }
+}
+
+fn median_features(stats: &[&FeatureStats]) -> FeatureStats {
+    let field = |count: fn(&FeatureStats) -> usize| {
+        median(stats.iter().map(|stats| count(stats)).collect())
+    };
+    let mut median_stats = FeatureStats {
+        total_nodes: field(|stats| stats.total_nodes),
+        v2_transport: field(|stats| stats.v2_transport),
+        compact_filters: field(|stats| stats.compact_filters),
+        v2_and_filters: field(|stats| stats.v2_and_filters),
+        service_flags: median_map(stats.iter().map(|stats| &stats.service_flags)),
+        combinations: median_map(stats.iter().map(|stats| &stats.combinations)),
+        protocol_versions: median_map(stats.iter().map(|stats| &stats.protocol_versions)),
+        ..FeatureStats::new()
+    };
+    for network in NetworkType::ALL {
+        let features: Vec<&ConnectionTypeFeatures> = stats
+            .iter()
+            .map(|stats| stats.connection_types.get(network))
+            .collect();
+        *median_stats.connection_types.get_mut(network) = median_connection_type(&features);
+    }
+    median_stats.update_confidence_intervals();
+
+    median_stats
+}
+
+/// Report of the median counts of reports, with the metadata of the last report.
+fn median_report(reports: &[CensusReport]) -> Option<CensusReport> {
+    let last = reports.last()?;
+    let stats: Vec<&FeatureStats> = reports.iter().map(|report| &report.stats).collect();
+
+    Some(CensusReport {
+        timestamp: median(reports.iter().map(|report| report.timestamp).collect()),
+        duration_seconds: median(
+            reports
+                .iter()
+                .map(|report| report.duration_seconds)
+                .collect(),
+        ),
+        total_contacted: median(
+            reports
+                .iter()
+                .map(|report| report.total_contacted)
+                .collect(),
+        ),
+        stats: median_features(&stats),
+        ..last.clone()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::tests::report;
+    use crate::Network;
+
+    fn compaction(aggregate: Aggregate) -> Compaction {
+        Compaction {
+            period: Period::Day,
+            aggregate,
+            keep_raw_seconds: None,
+            max_age_seconds: None,
+        }
+    }
+
+    /// Timestamps and node counts of reports.
+    fn summary(reports: &[CensusReport]) -> Vec<(u64, usize)> {
+        reports
+            .iter()
+            .map(|report| (report.timestamp, report.stats.total_nodes))
+            .collect()
+    }
+
+    #[test]
+    fn last_of_each_period() {
+        let reports = vec![
+            report(SECONDS_PER_DAY + 10, 3),
+            report(200, 2),
+            report(100, 1),
+        ];
+        let compacted = compaction(Aggregate::Last).apply(reports, 0);
+        assert_eq!(
+            summary(&compacted),
+            vec![(200, 2), (SECONDS_PER_DAY + 10, 3)]
+        );
+    }
+
+    #[test]
+    fn median_of_each_period() {
+        let reports = vec![report(100, 1), report(200, 5), report(300, 3)];
+        let compacted = compaction(Aggregate::Median).apply(reports, 0);
+        assert_eq!(summary(&compacted), vec![(200, 3)]);
+    }
+
+    #[test]
+    fn networks_are_compacted_separately() {
+        let mut signet = report(200, 2);
+        signet.network = Network::Signet;
+        let compacted = compaction(Aggregate::Last).apply(vec![report(100, 1), signet], 0);
+        assert_eq!(summary(&compacted), vec![(100, 1), (200, 2)]);
+    }
+
+    #[test]
+    fn raw_window_and_retention() {
+        let now = 10 * SECONDS_PER_DAY;
+        let mut partial = report(4 * SECONDS_PER_DAY + 10, 9);
+        partial.partial = true;
+        let reports = vec![
+            report(SECONDS_PER_DAY, 1),
+            report(4 * SECONDS_PER_DAY, 2),
+            partial,
+            report(4 * SECONDS_PER_DAY + 20, 3),
+            report(now - 20, 4),
+            report(now - 10, 5),
+        ];
+        let compaction = Compaction {
+            keep_raw_seconds: Some(SECONDS_PER_DAY),
+            max_age_seconds: Some(7 * SECONDS_PER_DAY),
+            ..compaction(Aggregate::Last)
+        };
+        let compacted = compaction.apply(reports, now);
+        assert_eq!(
+            summary(&compacted),
+            vec![(4 * SECONDS_PER_DAY + 20, 3), (now - 20, 4), (now - 10, 5)]
+        );
+    }
+
+    #[test]
+    fn periods() {
+        // 1970-01-01 was a Thursday, weeks start on Monday 1970-01-05.
+        assert_eq!(
+            Period::Week.index(0),
+            Period::Week.index(3 * SECONDS_PER_DAY)
+        );
+        assert_ne!(
+            Period::Week.index(0),
+            Period::Week.index(4 * SECONDS_PER_DAY)
+        );
+        assert_eq!(
+            Period::Month.index(0),
+            Period::Month.index(30 * SECONDS_PER_DAY)
+        );
+        assert_ne!(
+            Period::Month.index(0),
+            Period::Month.index(31 * SECONDS_PER_DAY)
+        );
+    }
+}
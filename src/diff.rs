@@ -0,0 +1,271 @@
+use crate::node::NetworkType;
+use crate::report::{date, CensusReport};
+use crate::stats::{percentage, Scope};
+use clap::ValueEnum;
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::fmt::{self, Write};
+
+/// Format of a report diff.
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum DiffFormat {
+    Table,
+    Json,
+    Markdown,
+}
+
+/// Change of a single metric between two reports.
+#[derive(Debug, Clone, Serialize)]
+pub struct MetricChange {
+    /// Connection type of the metric, `all` for the whole network.
+    pub connection_type: String,
+    /// Field name, or `<map>.<key>` for service flags, combinations and protocol versions.
+    pub metric: String,
+    pub before: usize,
+    pub after: usize,
+    /// Absolute change in nodes.
+    pub change: i64,
+    /// Percentage of the connection type's nodes, or of all nodes for `total_nodes`.
+    pub before_pct: f64,
+    pub after_pct: f64,
+    /// Change in percentage points.
+    pub change_pp: f64,
+}
+
+/// Changes of every metric between two reports.
+#[derive(Debug, Clone, Serialize)]
+pub struct ReportDiff {
+    pub before_timestamp: u64,
+    pub after_timestamp: u64,
+    pub changes: Vec<MetricChange>,
+}
+
+/// Every metric of a scope with its count, map entries missing from this report count zero.
+fn counts(scope: &Scope, other: &Scope) -> Vec<(String, usize)> {
+    let mut counts: Vec<(String, usize)> = scope
+        .fields()
+        .iter()
+        .map(|(name, count)| (name.to_string(), *count))
+        .collect();
+    for ((name, map), (_, other)) in scope.maps().iter().zip(other.maps().iter()) {
+        let keys: BTreeSet<&String> = map.keys().chain(other.keys()).collect();
+        for key in keys {
+            counts.push((format!("{name}.{key}"), map.get(key).copied().unwrap_or(0)));
+        }
+    }
+    counts
+}
+
+impl ReportDiff {
+    /// Compare every metric of two reports, overall and per connection type.
+    pub fn new(before: &CensusReport, after: &CensusReport) -> Self {
+        let scopes = std::iter::once(None)
+            .chain(NetworkType::ALL.map(Some))
+            .map(|network| {
+                (
+                    network.map_or_else(|| "all".to_string(), |network| network.to_string()),
+                    Scope::new(&before.stats, network),
+                    Scope::new(&after.stats, network),
+                )
+            });
+
+        let mut changes = Vec::new();
+        for (connection_type, before_scope, after_scope) in scopes {
+            let before_counts = counts(&before_scope, &after_scope);
+            let after_counts = counts(&after_scope, &before_scope);
+            for ((metric, before_count), (_, after_count)) in
+                before_counts.into_iter().zip(after_counts)
+            {
+                let (before_total, after_total) = if metric == "total_nodes" {
+                    (before.stats.total_nodes, after.stats.total_nodes)
+                } else {
+                    (before_scope.total_nodes, after_scope.total_nodes)
+                };
+                let before_pct = percentage(before_count, before_total);
+                let after_pct = percentage(after_count, after_total);
+                changes.push(MetricChange {
+                    connection_type: connection_type.clone(),
+                    metric,
+                    before: before_count,
+                    after: after_count,
+                    change: after_count as i64 - before_count as i64,
+                    before_pct,
+                    after_pct,
+                    change_pp: after_pct - before_pct,
+                });
+            }
+        }
+
+        Self {
+            before_timestamp: before.timestamp,
+            after_timestamp: after.timestamp,
+            changes,
+        }
+    }
+
+    pub fn format(&self, format: DiffFormat) -> Result<String, Box<dyn std::error::Error>> {
+        Ok(match format {
+            DiffFormat::Table => self.to_string(),
+            DiffFormat::Json => format!("{}\n", serde_json::to_string_pretty(self)?),
+            DiffFormat::Markdown => self.format_markdown()?,
+        })
+    }
+
+    fn format_markdown(&self) -> Result<String, fmt::Error> {
+        let mut out = String::new();
+        writeln!(
+            out,
+            "Census changes from {} to {}.\n",
+            date(self.before_timestamp),
+            date(self.after_timestamp)
+        )?;
+        writeln!(
+            out,
+            "| Connection type | Metric | Before | After | Change | Before % | After % | Change (pp) |"
+        )?;
+        writeln!(out, "|---|---|--:|--:|--:|--:|--:|--:|")?;
+        for change in &self.changes {
+            writeln!(
+                out,
+                "| {} | {} | {} | {} | {:+} | {:.1}% | {:.1}% | {:+.1} |",
+                change.connection_type,
+                change.metric,
+                change.before,
+                change.after,
+                change.change,
+                change.before_pct,
+                change.after_pct,
+                change.change_pp
+            )?;
+        }
+        Ok(out)
+    }
+}
+
+impl fmt::Display for ReportDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let width = self
+            .changes
+            .iter()
+            .map(|change| change.metric.len())
+            .max()
+            .unwrap_or(0)
+            .max("metric".len());
+        writeln!(
+            f,
+            "{:<15} {:<width$} {:>8} {:>8} {:>8} {:>9} {:>9} {:>8}",
+            "connection_type", "metric", "before", "after", "change", "before_%", "after_%", "pp"
+        )?;
+        for change in &self.changes {
+            writeln!(
+                f,
+                "{:<15} {:<width$} {:>8} {:>8} {:>+8} {:>9.1} {:>9.1} {:>+8.1}",
+                change.connection_type,
+                change.metric,
+                change.before,
+                change.after,
+                change.change,
+                change.before_pct,
+                change.after_pct,
+                change.change_pp
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::tests::report;
+
+    const DAY: u64 = 24 * 60 * 60;
+
+    /// Reports of 10 and then 20 nodes, each advertising a service flag the other does not.
+    fn reports() -> (CensusReport, CensusReport) {
+        let mut before = report(0, 10);
+        before.stats.v2_transport = 2;
+        before.stats.service_flags.insert("bloom".to_string(), 3);
+        let ipv4 = before.stats.connection_types.get_mut(NetworkType::Ipv4);
+        ipv4.total_nodes = 4;
+        ipv4.v2_transport = 1;
+
+        let mut after = report(DAY, 20);
+        after.stats.v2_transport = 10;
+        after.stats.service_flags.insert("witness".to_string(), 5);
+        let ipv4 = after.stats.connection_types.get_mut(NetworkType::Ipv4);
+        ipv4.total_nodes = 10;
+        ipv4.v2_transport = 5;
+
+        (before, after)
+    }
+
+    fn change<'a>(diff: &'a ReportDiff, connection_type: &str, metric: &str) -> &'a MetricChange {
+        diff.changes
+            .iter()
+            .find(|change| change.connection_type == connection_type && change.metric == metric)
+            .unwrap()
+    }
+
+    #[test]
+    fn metrics_on_one_side() {
+        let (before, after) = reports();
+        let diff = ReportDiff::new(&before, &after);
+
+        let bloom = change(&diff, "all", "service_flags.bloom");
+        assert_eq!((bloom.before, bloom.after, bloom.change), (3, 0, -3));
+        assert_eq!((bloom.before_pct, bloom.after_pct), (30.0, 0.0));
+        let witness = change(&diff, "all", "service_flags.witness");
+        assert_eq!((witness.before, witness.after, witness.change), (0, 5, 5));
+        assert_eq!(witness.change_pp, 25.0);
+
+        // The same metrics are compared in both directions.
+        let reverse = ReportDiff::new(&after, &before);
+        assert_eq!(reverse.changes.len(), diff.changes.len());
+        assert_eq!(change(&reverse, "all", "service_flags.bloom").change, 3);
+    }
+
+    #[test]
+    fn percentage_bases() {
+        let (before, after) = reports();
+        let diff = ReportDiff::new(&before, &after);
+
+        // Nodes of a connection type are a share of all nodes, its features of its own nodes.
+        let ipv4 = change(&diff, "ipv4", "total_nodes");
+        assert_eq!(
+            (ipv4.before_pct, ipv4.after_pct, ipv4.change_pp),
+            (40.0, 50.0, 10.0)
+        );
+        let ipv4_v2 = change(&diff, "ipv4", "v2_transport");
+        assert_eq!((ipv4_v2.before_pct, ipv4_v2.after_pct), (25.0, 50.0));
+        let all = change(&diff, "all", "total_nodes");
+        assert_eq!((all.before_pct, all.after_pct), (100.0, 100.0));
+        let empty = change(&diff, "tor_v3", "v2_transport");
+        assert_eq!((empty.before_pct, empty.after_pct), (0.0, 0.0));
+    }
+
+    #[test]
+    fn formats() {
+        let (before, after) = reports();
+        let diff = ReportDiff::new(&before, &after);
+
+        let table = diff.format(DiffFormat::Table).unwrap();
+        assert!(table.starts_with("connection_type"));
+        assert_eq!(table.lines().count(), diff.changes.len() + 1);
+
+        let json: serde_json::Value =
+            serde_json::from_str(&diff.format(DiffFormat::Json).unwrap()).unwrap();
+        assert_eq!(json["after_timestamp"], DAY);
+        assert_eq!(
+            json["changes"].as_array().unwrap().len(),
+            diff.changes.len()
+        );
+
+        let markdown = diff.format(DiffFormat::Markdown).unwrap();
+        assert!(markdown
+            .starts_with("Census changes from 1970-01-01 00:00 UTC to 1970-01-02 00:00 UTC.\n"));
+        assert!(
+            markdown.contains("| all | service_flags.bloom | 3 | 0 | -3 | 30.0% | 0.0% | -30.0 |")
+        );
+    }
+}
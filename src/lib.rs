@@ -1,5 +1,19 @@
+pub mod churn;
+pub mod compact;
+pub mod diff;
+pub mod network;
+pub mod node;
+pub mod query;
 pub mod report;
+pub mod seed;
+pub mod server;
 pub mod stats;
+#[cfg(feature = "sqlite")]
+pub mod store;
+pub mod trend;
 
-pub use report::{CensusReport, OutputFormat};
+pub use network::Network;
+pub use node::{NodeFormat, NodeObservation, NodeWriter};
+pub use query::FeatureQuery;
+pub use report::{CensusReport, CrawlConfig, OutputFormat, StopReason};
 pub use stats::NodeStats;
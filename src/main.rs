@@ -1,23 +1,37 @@
 use bitcoin::p2p::address::AddrV2;
-use bitcoin::Network;
-use bitcoin_peers_crawler::{CrawlerBuilder, CrawlerMessage, Peer, TransportPolicy};
-use clap::{Parser, Subcommand};
-use log::info;
-use std::path::PathBuf;
+#[cfg(feature = "sqlite")]
+use bitcoin_node_census::store::Store;
+use bitcoin_node_census::{
+    churn::NodeChurn,
+    compact::{Aggregate, Compaction, Period},
+    diff::{DiffFormat, ReportDiff},
+    node, query, report, seed, server,
+    trend::TrendCheck,
+    CensusReport, CrawlConfig, FeatureQuery, Network, NodeFormat, NodeObservation, NodeStats,
+    NodeWriter, OutputFormat, StopReason,
+};
+use bitcoin_peers_crawler::{Crawler, CrawlerBuilder, CrawlerMessage, Peer, TransportPolicy};
+use clap::{ArgGroup, Args, Parser, Subcommand};
+use log::{info, warn};
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::net::{IpAddr, SocketAddr};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
 use std::time::Instant;
-use std::{error::Error, net::IpAddr};
 use tokio::{
+    net::TcpListener,
     select,
+    sync::{mpsc, watch},
     time::{interval, Duration},
 };
 
-mod report;
-mod stats;
-
-pub use report::{CensusReport, OutputFormat};
-pub use stats::NodeStats;
-
 const USER_AGENT: &str = concat!("/census:", env!("CARGO_PKG_VERSION"), "/");
+const PROTOCOL_VERSION: u32 = 70016;
+const TRANSPORT_POLICY: TransportPolicy = TransportPolicy::V2Preferred;
+/// Delay before retrying the first failed census in daemon mode, doubled on each failure.
+const DAEMON_RETRY_DELAY: Duration = Duration::from_secs(60);
 
 #[derive(Parser)]
 #[command(
@@ -34,25 +48,314 @@ struct Cli {
     log_level: String,
 }
 
+#[derive(Args)]
+struct QueryArgs {
+    /// Feature combination to count, e.g. "pruned_v2=pruned AND v2" (repeatable).
+    #[arg(long = "query")]
+    queries: Vec<FeatureQuery>,
+    /// File of feature combinations to count, one per line.
+    #[arg(long)]
+    queries_file: Option<PathBuf>,
+}
+
+impl QueryArgs {
+    /// Collect the queries from both the command line and file, rejecting clashing names.
+    fn queries(&self) -> Result<Vec<FeatureQuery>, Box<dyn Error>> {
+        let mut queries = self.queries.clone();
+        if let Some(path) = &self.queries_file {
+            queries.extend(query::read_queries(path)?);
+        }
+        query::check_names(&queries)?;
+        Ok(queries)
+    }
+}
+
+#[derive(Args)]
+struct StoreArgs {
+    /// SQLite database to also store the report and any node observations in.
+    #[cfg(feature = "sqlite")]
+    #[arg(long)]
+    db: Option<PathBuf>,
+}
+
+impl StoreArgs {
+    /// Store the report and the node observations of its crawl, if a database is configured.
+    ///
+    /// The observations are only loaded when they are stored.
+    #[cfg_attr(not(feature = "sqlite"), allow(unused_variables))]
+    fn store(
+        &self,
+        report: &CensusReport,
+        nodes: impl FnOnce() -> Result<Vec<NodeObservation>, Box<dyn Error>>,
+    ) -> Result<(), Box<dyn Error>> {
+        #[cfg(feature = "sqlite")]
+        if let Some(path) = &self.db {
+            let id = Store::open(path)?.insert_report(report, &nodes()?)?;
+            info!("Census stored in {} as report {id}", path.display());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Args)]
+struct CrawlArgs {
+    /// Bitcoin network to crawl.
+    #[arg(short, long, value_enum, default_value = "mainnet")]
+    network: Network,
+    /// Seed node as host[:port] (repeatable, defaults to a seed of the network). Seeds are
+    /// tried in order until one connects, or all crawled with `--crawl-all-seeds`.
+    #[arg(short, long = "seed", alias = "address", short_alias = 'a')]
+    seeds: Vec<String>,
+    /// File of seed nodes, one host[:port] per line.
+    #[arg(long)]
+    seeds_file: Option<PathBuf>,
+    /// Default seed node port (defaults to the port of the network).
+    #[arg(short = 'p', long)]
+    port: Option<u16>,
+    /// Try every address a seed hostname resolves to, not just the first.
+    #[arg(long)]
+    expand_dns: bool,
+    /// Crawl from every seed and merge the nodes, so a seed in a partition of the network does
+    /// not decide the census.
+    #[arg(long)]
+    crawl_all_seeds: bool,
+    /// Maximum concurrent connections.
+    #[arg(short, long, default_value = "32")]
+    concurrent: usize,
+    /// Stop the crawl after this long, e.g. 90s, 30m, 6h or 1d.
+    #[arg(long, value_parser = parse_duration)]
+    max_duration: Option<Duration>,
+    /// Stop the crawl after this many listening nodes.
+    #[arg(long)]
+    max_nodes: Option<NonZeroUsize>,
+    #[command(flatten)]
+    queries: QueryArgs,
+    /// Identifier of this host recorded in the report (defaults to the hostname).
+    #[arg(long, env = "CENSUS_HOST")]
+    host: Option<String>,
+}
+
+impl CrawlArgs {
+    /// Resolve the seeds and collect the configuration of a crawl.
+    async fn config(&self) -> Result<CrawlConfig, Box<dyn Error>> {
+        let mut seeds = self.seeds.clone();
+        if let Some(path) = &self.seeds_file {
+            seeds.extend(seed::read_seeds(path)?);
+        }
+        if seeds.is_empty() {
+            seeds.push(self.network.default_seed().to_string());
+        }
+        let port = self.port.unwrap_or(self.network.default_port());
+
+        Ok(CrawlConfig {
+            seeds: seed::resolve_seeds(&seeds, port, self.expand_dns).await?,
+            crawl_all_seeds: self.crawl_all_seeds,
+            concurrent: self.concurrent,
+            transport_policy: TRANSPORT_POLICY.to_string(),
+            protocol_version: PROTOCOL_VERSION,
+            user_agent: USER_AGENT.to_string(),
+            host: self.host.clone().or_else(hostname),
+            max_duration_seconds: self.max_duration.map(|duration| duration.as_secs()),
+            max_nodes: self.max_nodes.map(NonZeroUsize::get),
+        })
+    }
+
+    /// Empty stats for a crawl.
+    fn stats(&self) -> Result<NodeStats, Box<dyn Error>> {
+        Ok(NodeStats::with_queries(self.queries.queries()?))
+    }
+}
+
+#[derive(Args)]
+struct DaemonArgs {
+    #[command(flatten)]
+    crawl: CrawlArgs,
+    /// History file each report is appended to as JSONL.
+    #[arg(long, default_value = "census.jsonl")]
+    history: PathBuf,
+    /// Time between the start of each census, e.g. 6h or 7d.
+    #[arg(long, value_parser = parse_duration, default_value = "7d")]
+    interval: Duration,
+    /// Cron expression (UTC, with seconds) to run the census on instead of an interval,
+    /// e.g. "0 0 0 * * Sun".
+    #[arg(long, value_parser = parse_cron, conflicts_with = "interval")]
+    cron: Option<Box<cron::Schedule>>,
+    #[command(flatten)]
+    store: StoreArgs,
+}
+
+impl DaemonArgs {
+    /// When the daemon runs a census.
+    fn schedule(&self) -> Schedule {
+        match &self.cron {
+            Some(cron) => Schedule::Cron(cron.clone()),
+            None => Schedule::Interval(self.interval),
+        }
+    }
+}
+
 #[derive(Subcommand)]
 enum Commands {
     /// Run a census of the bitcoin network.
     Run {
-        /// Seed node address (IP or hostname).
-        #[arg(short = 'a', long, default_value = "seed.bitcoin.sipa.be")]
-        address: String,
-        /// Seed node port.
-        #[arg(short = 'p', long, default_value = "8333")]
-        port: u16,
-        /// Maximum concurrent connections.
-        #[arg(short, long, default_value = "32")]
-        concurrent: usize,
+        #[command(flatten)]
+        crawl: CrawlArgs,
         /// Output format.
         #[arg(short, long, value_enum, default_value = "json")]
         format: OutputFormat,
         /// Output file (stdout if not specified).
         #[arg(short, long)]
         output: Option<PathBuf>,
+        /// Append the report to the output file, a JSONL or CSV history, instead of overwriting it.
+        #[arg(long, requires = "output")]
+        append: bool,
+        /// Also write every observed node to this file.
+        #[arg(long)]
+        nodes: Option<PathBuf>,
+        /// Node observations format.
+        #[arg(long, value_enum, default_value = "jsonl")]
+        nodes_format: NodeFormat,
+        /// Append an interim report to this JSONL file at every progress interval.
+        #[arg(long)]
+        snapshots: Option<PathBuf>,
+        #[command(flatten)]
+        store: StoreArgs,
+    },
+    /// Run a census on a schedule, appending every report to a history file.
+    Daemon {
+        #[command(flatten)]
+        daemon: DaemonArgs,
+    },
+    /// Serve the dashboard and census history over HTTP.
+    Serve {
+        /// Address to listen on.
+        #[arg(long, default_value = "127.0.0.1:8000")]
+        listen: SocketAddr,
+        /// Also run the census daemon, appending to the served history.
+        #[arg(long = "daemon")]
+        with_daemon: bool,
+        #[command(flatten)]
+        daemon: DaemonArgs,
+    },
+    /// Recompute a census report from saved node observations.
+    Recompute {
+        /// Node observations file written by `run --nodes`.
+        #[arg(short, long)]
+        input: PathBuf,
+        /// Node observations format.
+        #[arg(long, value_enum, default_value = "jsonl")]
+        nodes_format: NodeFormat,
+        /// Bitcoin network the observations were taken of.
+        #[arg(short, long, value_enum, default_value = "mainnet")]
+        network: Network,
+        /// Output format.
+        #[arg(short, long, value_enum, default_value = "json")]
+        format: OutputFormat,
+        /// Output file (stdout if not specified).
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// Append the report to the output file, a JSONL or CSV history, instead of overwriting it.
+        #[arg(long, requires = "output")]
+        append: bool,
+        #[command(flatten)]
+        queries: QueryArgs,
+        #[command(flatten)]
+        store: StoreArgs,
+    },
+    /// Compare two reports, showing the change of every metric.
+    Diff {
+        /// Earlier report, a JSON file or the last report of a JSONL history.
+        before: PathBuf,
+        /// Later report, a JSON file or the last report of a JSONL history.
+        after: PathBuf,
+        /// Output format.
+        #[arg(short, long, value_enum, default_value = "table")]
+        format: DiffFormat,
+        /// Output file (stdout if not specified).
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Downsample a history of reports and apply a retention policy.
+    #[command(group(ArgGroup::new("destination").required(true).args(["output", "in_place"])))]
+    Compact {
+        /// History file of reports as JSONL.
+        #[arg(long, default_value = "census.jsonl")]
+        history: PathBuf,
+        /// Write the compacted history here.
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// Rewrite the history in place instead, keeping the original as `<history>.bak`.
+        #[arg(long)]
+        in_place: bool,
+        /// Period reports are downsampled to.
+        #[arg(long, value_enum, default_value = "week")]
+        period: Period,
+        /// How the reports of a period are combined.
+        #[arg(long, value_enum, default_value = "last")]
+        aggregate: Aggregate,
+        /// Keep reports younger than this as is, e.g. 90d.
+        #[arg(long, value_parser = parse_duration)]
+        keep_raw: Option<Duration>,
+        /// Drop reports older than this, e.g. 1825d.
+        #[arg(long, value_parser = parse_duration)]
+        max_age: Option<Duration>,
+    },
+    /// Check the latest report of a history against the trend of the reports before it.
+    ///
+    /// Every metric beyond its threshold is printed and the command exits with an error.
+    Check {
+        /// History file of reports as JSONL.
+        #[arg(long, default_value = "census.jsonl")]
+        history: PathBuf,
+        /// Number of reports before the latest the trend is fitted to, at least one.
+        #[arg(long, default_value = "8")]
+        window: NonZeroUsize,
+        /// Metric whose share of listening nodes is checked: a feature, service flag or
+        /// combination (can be specified multiple times).
+        #[arg(
+            long = "metric",
+            default_values = ["v2_transport", "compact_filters", "v2_and_filters"]
+        )]
+        metrics: Vec<String>,
+        /// Largest allowed deviation of a share from its trend, in percentage points.
+        #[arg(long, default_value = "5")]
+        share_threshold: f64,
+        /// Largest allowed deviation of a node count from its trend, in percent.
+        #[arg(long, default_value = "20")]
+        nodes_threshold: f64,
+        /// Skip connection types with fewer listening nodes than this by trend, at least one.
+        #[arg(long, default_value = "10")]
+        min_nodes: NonZeroUsize,
+    },
+    /// Show the churn of listening nodes and their feature transitions between the two latest
+    /// crawls, and their median lifetime.
+    Churn {
+        /// Node observations files written by `run --nodes`, one per crawl.
+        nodes: Vec<PathBuf>,
+        /// Node observations format.
+        #[arg(long, value_enum, default_value = "jsonl")]
+        nodes_format: NodeFormat,
+        /// Also use the node observations of the complete reports in a SQLite database written
+        /// with `--db`.
+        #[cfg(feature = "sqlite")]
+        #[arg(long)]
+        db: Option<PathBuf>,
+        /// Output format.
+        #[arg(short, long, value_enum, default_value = "table")]
+        format: DiffFormat,
+        /// Output file (stdout if not specified).
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Export the reports of a SQLite database as JSONL.
+    #[cfg(feature = "sqlite")]
+    Export {
+        /// SQLite database written with `--db`.
+        #[arg(long)]
+        db: PathBuf,
+        /// Output file (stdout if not specified).
+        #[arg(short, long)]
+        output: Option<PathBuf>,
     },
 }
 
@@ -85,13 +388,169 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     match cli.command {
         Commands::Run {
-            address,
-            port,
-            concurrent,
+            crawl,
             format,
             output,
+            append,
+            nodes,
+            nodes_format,
+            snapshots,
+            store,
         } => {
-            run_census(address, port, concurrent, format, output).await?;
+            let config = crawl.config().await?;
+            let stats = crawl.stats()?;
+            let node_writer = nodes
+                .as_ref()
+                .map(|path| NodeWriter::create(path, nodes_format))
+                .transpose()?;
+            let snapshot_file = snapshots
+                .map(|path| OpenOptions::new().create(true).append(true).open(path))
+                .transpose()?;
+            let report = run_census(
+                crawl.network,
+                config,
+                node_writer,
+                snapshot_file,
+                None,
+                stats,
+                shutdown_flag(),
+            )
+            .await?;
+            write_report(&report, format, output, append)?;
+            store.store(&report, || match &nodes {
+                Some(path) => node::read_observations(path, nodes_format),
+                None => Ok(Vec::new()),
+            })?;
+        }
+        Commands::Daemon { daemon } => {
+            run_daemon(&daemon, None).await?;
+        }
+        Commands::Serve {
+            listen,
+            with_daemon,
+            daemon,
+        } => {
+            let listener = TcpListener::bind(listen).await?;
+            info!("Serving on http://{}", listener.local_addr()?);
+            let (progress, progress_receiver) = watch::channel(None);
+            let server = server::serve(
+                listener,
+                daemon.history.clone(),
+                progress_receiver,
+                shutdown_signal(),
+            );
+            if with_daemon {
+                let (served, ran) = tokio::join!(server, run_daemon(&daemon, Some(&progress)));
+                served?;
+                ran?;
+            } else {
+                server.await?;
+            }
+        }
+        Commands::Recompute {
+            input,
+            nodes_format,
+            network,
+            format,
+            output,
+            append,
+            queries,
+            store,
+        } => {
+            let stats = NodeStats::with_queries(queries.queries()?);
+            let (report, observations) = recompute(&input, nodes_format, network, stats)?;
+            write_report(&report, format, output, append)?;
+            store.store(&report, || Ok(observations))?;
+        }
+        Commands::Diff {
+            before,
+            after,
+            format,
+            output,
+        } => {
+            let diff = ReportDiff::new(
+                &report::read_report(&before)?,
+                &report::read_report(&after)?,
+            );
+            let content = diff.format(format)?;
+            match output {
+                Some(path) => std::fs::write(path, content)?,
+                None => print!("{content}"),
+            }
+        }
+        Commands::Compact {
+            history,
+            output,
+            // Implied by a missing output, one of them is required.
+            in_place: _,
+            period,
+            aggregate,
+            keep_raw,
+            max_age,
+        } => {
+            let compaction = Compaction {
+                period,
+                aggregate,
+                keep_raw_seconds: keep_raw.map(|duration| duration.as_secs()),
+                max_age_seconds: max_age.map(|duration| duration.as_secs()),
+            };
+            compact(&history, output, compaction)?;
+        }
+        Commands::Check {
+            history,
+            window,
+            metrics,
+            share_threshold,
+            nodes_threshold,
+            min_nodes,
+        } => {
+            let check = TrendCheck {
+                window: window.get(),
+                metrics,
+                share_threshold,
+                nodes_threshold,
+                min_nodes: min_nodes.get(),
+            };
+            let deviations = check.check(&report::read_history(&history)?)?;
+            for deviation in &deviations {
+                println!("{deviation}");
+            }
+            if !deviations.is_empty() {
+                return Err(
+                    format!("{} metrics deviate from their trend", deviations.len()).into(),
+                );
+            }
+            info!("Latest census is within its trend");
+        }
+        Commands::Churn {
+            nodes,
+            nodes_format,
+            #[cfg(feature = "sqlite")]
+            db,
+            format,
+            output,
+        } => {
+            let crawls = nodes
+                .iter()
+                .map(|path| node::read_observations(path, nodes_format))
+                .collect::<Result<Vec<_>, _>>()?;
+            #[cfg(feature = "sqlite")]
+            let crawls = match db {
+                Some(db) => [crawls, stored_crawls(&db)?].concat(),
+                None => crawls,
+            };
+
+            let churn = NodeChurn::new(&crawls)
+                .ok_or("churn needs node observations of at least two crawls")?;
+            let content = churn.format(format)?;
+            match output {
+                Some(path) => std::fs::write(path, content)?,
+                None => print!("{content}"),
+            }
+        }
+        #[cfg(feature = "sqlite")]
+        Commands::Export { db, output } => {
+            export(&db, output)?;
         }
     }
 
@@ -99,80 +558,563 @@ async fn main() -> Result<(), Box<dyn Error>> {
 }
 
 async fn run_census(
-    address: String,
-    port: u16,
-    concurrent: usize,
-    format: OutputFormat,
-    output: Option<PathBuf>,
-) -> Result<(), Box<dyn Error>> {
-    info!("BITCOIN NODE CENSUS");
-    info!("Seed {address}:{port}, {concurrent} concurrent connections");
+    network: Network,
+    config: CrawlConfig,
+    mut node_writer: Option<NodeWriter>,
+    mut snapshot_file: Option<File>,
+    progress: Option<&watch::Sender<Option<CensusReport>>>,
+    mut stats: NodeStats,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<CensusReport, Box<dyn Error>> {
+    info!("BITCOIN NODE CENSUS ({network})");
+    for seed in &config.seeds {
+        info!("Seed {seed}");
+    }
+    info!("{} concurrent connections", config.concurrent);
+    if let Some(seconds) = config.max_duration_seconds {
+        info!("Stopping after {seconds} seconds");
+    }
+    if let Some(max_nodes) = config.max_nodes {
+        info!("Stopping after {max_nodes} listening nodes");
+    }
 
     let process_start = Instant::now();
-    let mut stats = NodeStats::new();
 
-    let crawler = CrawlerBuilder::new(Network::Bitcoin)
-        .with_max_concurrent_tasks(concurrent)
-        .with_transport_policy(TransportPolicy::V2Preferred)
-        .with_protocol_version(70016)
+    let crawler = CrawlerBuilder::new(network.to_bitcoin())
+        .with_max_concurrent_tasks(config.concurrent)
+        .with_transport_policy(TRANSPORT_POLICY)
+        .with_protocol_version(PROTOCOL_VERSION)
         .with_user_agent(USER_AGENT)?
         .build();
 
-    let ip_addr = tokio::net::lookup_host(format!("{address}:{port}"))
+    // A crawl usually covers the whole network, so seeds are only fallbacks for each other and
+    // the next seed is tried when a crawl ends without reaching a listening node. Crawling from
+    // every seed instead merges the crawls, recording each node once.
+    let mut seeds = config.seeds.iter();
+    let mut receiver = start_crawl(&crawler, &mut seeds)
         .await?
-        .next()
-        .ok_or("Failed to resolve seed address")?
-        .ip();
-
-    let addr = match ip_addr {
-        IpAddr::V4(ipv4) => AddrV2::Ipv4(ipv4),
-        IpAddr::V6(ipv6) => AddrV2::Ipv6(ipv6),
-    };
-
-    let seed = Peer::new(addr, port);
-    let mut receiver = crawler
-        .crawl(seed)
-        .await
-        .map_err(|e| format!("Failed to start crawler: {e}"))?;
+        .ok_or("no seeds to crawl from")?;
+    let mut connected = false;
+    // Failed seeds, and with every seed crawled any non-listening node, may still be reached by
+    // the crawl of a later seed, so they are only counted as non-listening if they never are.
+    let mut non_listening = Vec::new();
+    let mut seen: HashSet<(AddrV2, u16)> = HashSet::new();
 
     let mut progress_interval = interval(Duration::from_secs(60));
     progress_interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
 
-    loop {
+    let shutdown = shutdown_requested(&mut shutdown);
+    tokio::pin!(shutdown);
+
+    let max_duration = config.max_duration_seconds.map(Duration::from_secs);
+    let deadline = async move {
+        match max_duration {
+            Some(duration) => tokio::time::sleep(duration).await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::pin!(deadline);
+
+    let stop_reason = loop {
         select! {
             msg = receiver.recv() => {
-                // Only care about listening nodes for stats, break when channel closed.
                 match msg {
-                    Some(CrawlerMessage::Listening(peer)) => {
-                        stats.increment_contacted();
-                        stats.add_node(peer);
+                    Some(CrawlerMessage::NonListening(peer))
+                        if !connected || config.crawl_all_seeds =>
+                    {
+                        non_listening.push(peer);
                     }
-                    Some(CrawlerMessage::NonListening(_)) => {
-                        stats.increment_contacted();
+                    Some(msg) => {
+                        connected |= matches!(msg, CrawlerMessage::Listening(_));
+                        record_message(msg, &mut seen, &mut stats, &mut node_writer)?;
                     }
-                    None => break,
+                    // Break when the crawl is done, unless it never got past its seed.
+                    None if connected && !config.crawl_all_seeds => break None,
+                    None => match start_crawl(&crawler, &mut seeds).await? {
+                        Some(next) => receiver = next,
+                        None => break None,
+                    },
+                }
+                if config.max_nodes.is_some_and(|max| stats.total_nodes() >= max) {
+                    info!("Reached maximum nodes, stopping crawl");
+                    break Some(StopReason::MaxNodes);
                 }
             }
             _ = progress_interval.tick() => {
                 let elapsed = process_start.elapsed().as_secs();
                 stats.set_duration(elapsed);
-                let report = CensusReport::from_stats(&stats);
+                let mut report = CensusReport::from_stats(&stats, network).with_config(config.clone());
+                report.partial = true;
                 info!("{report}");
+                if let Some(file) = snapshot_file.as_mut() {
+                    report.write_jsonl(file)?;
+                }
+                if let Some(progress) = progress {
+                    progress.send_replace(Some(report));
+                }
+            }
+            _ = &mut shutdown => {
+                info!("Shutdown requested, stopping crawl");
+                break Some(StopReason::Interrupted);
+            }
+            _ = &mut deadline => {
+                info!("Reached maximum duration, stopping crawl");
+                break Some(StopReason::MaxDuration);
             }
         }
+    };
+
+    // Closing the channel stops the crawls, but still count the nodes they already reported.
+    // Unless the node limit was hit, which would then be overshot.
+    if matches!(
+        stop_reason,
+        Some(StopReason::Interrupted | StopReason::MaxDuration)
+    ) {
+        receiver.close();
+        while let Some(msg) = receiver.recv().await {
+            match msg {
+                CrawlerMessage::NonListening(peer) if config.crawl_all_seeds => {
+                    non_listening.push(peer);
+                }
+                msg => record_message(msg, &mut seen, &mut stats, &mut node_writer)?,
+            }
+        }
+    }
+    for peer in non_listening {
+        record_message(
+            CrawlerMessage::NonListening(peer),
+            &mut seen,
+            &mut stats,
+            &mut node_writer,
+        )?;
     }
 
     let duration = process_start.elapsed();
     info!(
-        "Census complete: {} listening nodes out of {} contacted in {:.1} seconds",
+        "Census {}: {} listening nodes out of {} contacted in {:.1} seconds",
+        if stop_reason.is_some() {
+            "incomplete"
+        } else {
+            "complete"
+        },
         stats.total_nodes(),
         stats.total_contacted(),
         duration.as_secs_f64()
     );
 
+    if let Some(writer) = node_writer.as_mut() {
+        writer.flush()?;
+    }
+
     stats.set_duration(duration.as_secs());
-    let report = CensusReport::from_stats(&stats);
-    report.write(format, output)?;
+    let mut report = CensusReport::from_stats(&stats, network).with_config(config);
+    report.stop_reason = stop_reason;
+
+    Ok(report)
+}
+
+/// Parse a duration from a number with an optional unit suffix (s, m, h, d), seconds by default.
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let (value, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, "s"),
+    };
+    let value: u64 = value
+        .parse()
+        .map_err(|_| format!("invalid duration: {s}"))?;
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 60 * 60 * 24,
+        _ => return Err(format!("invalid duration unit: {unit}")),
+    };
+    let seconds = value
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("duration too large: {s}"))?;
+
+    Ok(Duration::from_secs(seconds))
+}
+
+/// Start a crawl from the next seed, none if there are no seeds left.
+async fn start_crawl(
+    crawler: &Crawler,
+    seeds: &mut std::slice::Iter<'_, SocketAddr>,
+) -> Result<Option<mpsc::Receiver<CrawlerMessage>>, Box<dyn Error>> {
+    let Some(seed) = seeds.next() else {
+        return Ok(None);
+    };
+    info!("Crawling from seed {seed}");
+    let addr = match seed.ip() {
+        IpAddr::V4(ipv4) => AddrV2::Ipv4(ipv4),
+        IpAddr::V6(ipv6) => AddrV2::Ipv6(ipv6),
+    };
+    let receiver = crawler
+        .crawl(Peer::new(addr, seed.port()))
+        .await
+        .map_err(|e| format!("Failed to start crawler: {e}"))?;
+
+    Ok(Some(receiver))
+}
+
+/// Count a crawler message in the stats.
+fn record_message(
+    msg: CrawlerMessage,
+    seen: &mut HashSet<(AddrV2, u16)>,
+    stats: &mut NodeStats,
+    node_writer: &mut Option<NodeWriter>,
+) -> Result<(), Box<dyn Error>> {
+    // Only care about listening nodes for stats.
+    match msg {
+        CrawlerMessage::Listening(peer) | CrawlerMessage::NonListening(peer)
+            if !seen.insert((peer.address.clone(), peer.port)) =>
+        {
+            // Already counted, e.g. a failed seed reached by the crawl of another seed.
+        }
+        CrawlerMessage::Listening(peer) => {
+            if let Some(writer) = node_writer.as_mut() {
+                writer.write(&NodeObservation::from_peer(&peer, true, report::now()))?;
+            }
+            stats.increment_contacted();
+            stats.add_node(peer);
+        }
+        CrawlerMessage::NonListening(peer) => {
+            if let Some(writer) = node_writer.as_mut() {
+                writer.write(&NodeObservation::from_peer(&peer, false, report::now()))?;
+            }
+            stats.increment_contacted();
+        }
+    }
 
     Ok(())
 }
+
+/// Resolves once the process is asked to stop with Ctrl-C or SIGTERM.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+            }
+            Err(e) => {
+                warn!("Failed to listen for SIGTERM: {e}");
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+/// Listen for a shutdown signal in the background, so one arriving between waits on the returned
+/// flag is not lost.
+fn shutdown_flag() -> watch::Receiver<bool> {
+    let (sender, receiver) = watch::channel(false);
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        sender.send_replace(true);
+    });
+    receiver
+}
+
+/// Resolves once the shutdown flag is set, immediately if it already is.
+async fn shutdown_requested(shutdown: &mut watch::Receiver<bool>) {
+    let _ = shutdown.wait_for(|requested| *requested).await;
+}
+
+/// When the daemon runs a census.
+enum Schedule {
+    /// Fixed time between the start of each census, the first starting immediately.
+    Interval(Duration),
+    /// Start a census at every upcoming time of a cron expression.
+    Cron(Box<cron::Schedule>),
+}
+
+impl Schedule {
+    /// Time to wait until the next census, given when the previous one started.
+    fn until_next(&self, started: Instant) -> Duration {
+        match self {
+            Schedule::Interval(interval) => interval.saturating_sub(started.elapsed()),
+            Schedule::Cron(cron) => cron
+                .upcoming(chrono::Utc)
+                .next()
+                .and_then(|next| (next - chrono::Utc::now()).to_std().ok())
+                .unwrap_or(Duration::MAX),
+        }
+    }
+}
+
+/// Write a report to the output, appending to it as a history if requested.
+fn write_report(
+    report: &CensusReport,
+    format: OutputFormat,
+    output: Option<PathBuf>,
+    append: bool,
+) -> Result<(), Box<dyn Error>> {
+    match output {
+        Some(path) if append => report.append(format, &path),
+        output => report.write(format, output),
+    }
+}
+
+/// Run a census on a schedule until shutdown, appending each report to the history file.
+///
+/// A failed census is retried with exponential backoff, capped at the time until the next
+/// scheduled census.
+async fn run_daemon(
+    daemon: &DaemonArgs,
+    progress: Option<&watch::Sender<Option<CensusReport>>>,
+) -> Result<(), Box<dyn Error>> {
+    let crawl = &daemon.crawl;
+    let history = &daemon.history;
+    let schedule = daemon.schedule();
+    let mut shutdown = shutdown_flag();
+    info!("Census daemon started, appending to {}", history.display());
+    let mut failures: u32 = 0;
+    let mut wait = match schedule {
+        Schedule::Interval(_) => Duration::ZERO,
+        Schedule::Cron(_) => schedule.until_next(Instant::now()),
+    };
+
+    loop {
+        if *shutdown.borrow() {
+            break;
+        }
+        if !wait.is_zero() {
+            info!("Next census in {} seconds", wait.as_secs());
+            select! {
+                _ = tokio::time::sleep(wait) => {}
+                _ = shutdown_requested(&mut shutdown) => break,
+            }
+        }
+
+        let started = Instant::now();
+        let result = async {
+            let config = crawl.config().await?;
+            let stats = crawl.stats()?;
+            let report = run_census(
+                crawl.network,
+                config,
+                None,
+                None,
+                progress,
+                stats,
+                shutdown.clone(),
+            )
+            .await?;
+            report.append(OutputFormat::Jsonl, history)?;
+            daemon.store.store(&report, || Ok(Vec::new()))?;
+            Ok::<_, Box<dyn Error>>(())
+        }
+        .await;
+        if let Some(progress) = progress {
+            progress.send_replace(None);
+        }
+
+        wait = match result {
+            Ok(_) => {
+                failures = 0;
+                info!("Census appended to {}", history.display());
+                schedule.until_next(started)
+            }
+            Err(e) => {
+                failures = failures.saturating_add(1);
+                let backoff = DAEMON_RETRY_DELAY
+                    .saturating_mul(2u32.saturating_pow(failures - 1))
+                    .min(schedule.until_next(started));
+                warn!(
+                    "Census failed ({failures} in a row), retrying in {} seconds: {e}",
+                    backoff.as_secs()
+                );
+                backoff
+            }
+        };
+    }
+
+    info!("Census daemon stopped");
+    Ok(())
+}
+
+/// Compact a history into the output, or in place with a backup if there is none.
+fn compact(
+    history: &Path,
+    output: Option<PathBuf>,
+    compaction: Compaction,
+) -> Result<(), Box<dyn Error>> {
+    let compact = |reports: Vec<CensusReport>| {
+        let before = reports.len();
+        let reports = compaction.apply(reports, report::now());
+        info!("Compacted {before} reports into {}", reports.len());
+        reports
+    };
+
+    match output {
+        Some(path) => {
+            let mut content = Vec::new();
+            for report in compact(report::read_history(history)?) {
+                report.write_jsonl(&mut content)?;
+            }
+            std::fs::write(path, content)?;
+        }
+        None => {
+            report::rewrite_history(history, compact)?;
+            info!("Original history kept in {}.bak", history.display());
+        }
+    }
+
+    Ok(())
+}
+
+/// Export the reports of a database as JSONL, oldest first.
+#[cfg(feature = "sqlite")]
+fn export(db: &Path, output: Option<PathBuf>) -> Result<(), Box<dyn Error>> {
+    use std::io::{BufWriter, Write};
+
+    let mut out: Box<dyn Write> = match output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(std::io::stdout().lock()),
+    };
+    for (_, report) in Store::open(db)?.reports()? {
+        report.write_jsonl(&mut out)?;
+    }
+
+    Ok(())
+}
+
+/// Node observations of the complete reports in a database of the latest report's network.
+#[cfg(feature = "sqlite")]
+fn stored_crawls(db: &Path) -> Result<Vec<Vec<NodeObservation>>, Box<dyn Error>> {
+    let store = Store::open(db)?;
+    let reports = store.reports()?;
+    let Some((_, latest)) = reports.iter().rfind(|(_, report)| !report.partial) else {
+        return Ok(Vec::new());
+    };
+
+    let mut crawls = Vec::new();
+    for (id, report) in &reports {
+        if report.partial || report.network != latest.network {
+            continue;
+        }
+        let nodes = store.nodes(*id)?;
+        if !nodes.is_empty() {
+            crawls.push(nodes);
+        }
+    }
+    Ok(crawls)
+}
+
+fn recompute(
+    input: &Path,
+    nodes_format: NodeFormat,
+    network: Network,
+    mut stats: NodeStats,
+) -> Result<(CensusReport, Vec<NodeObservation>), Box<dyn Error>> {
+    let observations = node::read_observations(input, nodes_format)?;
+    info!(
+        "Recomputing census from {} node observations",
+        observations.len()
+    );
+
+    for observation in &observations {
+        stats.add_observation(observation);
+    }
+
+    // Observation timestamps bound the original crawl.
+    let timestamps = observations.iter().map(|o| o.timestamp);
+    let (Some(first), Some(last)) = (timestamps.clone().min(), timestamps.max()) else {
+        return Err(format!("no node observations in {}", input.display()).into());
+    };
+    stats.set_duration(last - first);
+
+    let mut report = CensusReport::from_stats(&stats, network);
+    report.timestamp = last;
+
+    Ok((report, observations))
+}
+
+/// Parse a cron expression with a seconds field, e.g. `0 0 0 * * Sun`.
+fn parse_cron(s: &str) -> Result<Box<cron::Schedule>, String> {
+    s.parse()
+        .map(Box::new)
+        .map_err(|e| format!("invalid cron expression: {e}"))
+}
+
+/// Hostname of the machine, if it can be determined.
+fn hostname() -> Option<String> {
+    std::env::var("HOSTNAME")
+        .or_else(|_| std::env::var("COMPUTERNAME"))
+        .ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration(" 30m "), Ok(Duration::from_secs(30 * 60)));
+        assert_eq!(parse_duration("6h"), Ok(Duration::from_secs(6 * 60 * 60)));
+        assert_eq!(
+            parse_duration("7d"),
+            Ok(Duration::from_secs(7 * 24 * 60 * 60))
+        );
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("h").is_err());
+        assert!(parse_duration("5w").is_err());
+        assert!(parse_duration("1.5h").is_err());
+        assert!(parse_duration("-1s").is_err());
+        assert!(parse_duration("300000000000000000d").is_err());
+    }
+
+    #[test]
+    fn interval_schedule() {
+        let schedule = Schedule::Interval(Duration::from_secs(60));
+        let wait = schedule.until_next(Instant::now());
+        assert!(wait <= Duration::from_secs(60) && wait > Duration::from_secs(59));
+
+        let Some(long_ago) = Instant::now().checked_sub(Duration::from_secs(120)) else {
+            return;
+        };
+        assert_eq!(schedule.until_next(long_ago), Duration::ZERO);
+    }
+
+    #[test]
+    fn cron_schedule() {
+        assert!(parse_cron("0 0 0 * * *").is_ok());
+        assert!(parse_cron("0 0 * *").is_err());
+
+        let schedule = Schedule::Cron(parse_cron("0 0 0 * * *").unwrap());
+        assert!(schedule.until_next(Instant::now()) <= Duration::from_secs(24 * 60 * 60));
+
+        // A schedule without upcoming times never runs again.
+        let past = Schedule::Cron(parse_cron("0 0 0 1 1 * 2000").unwrap());
+        assert_eq!(past.until_next(Instant::now()), Duration::MAX);
+    }
+
+    #[test]
+    fn recompute_needs_observations() {
+        let path = std::env::temp_dir().join(format!("census-{}-empty.jsonl", std::process::id()));
+        for contents in ["", "\n  \n"] {
+            std::fs::write(&path, contents).unwrap();
+            let error = recompute(&path, NodeFormat::Jsonl, Network::Mainnet, NodeStats::new())
+                .unwrap_err();
+            assert!(error.to_string().starts_with("no node observations"));
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,203 @@
+use bitcoin::p2p::address::AddrV2;
+use bitcoin_peers_crawler::{Peer, PeerProtocolVersion, PeerServices};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum NodeFormat {
+    Jsonl,
+    Csv,
+}
+
+/// Network type of a node address, mirrors the [`AddrV2`] variants.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NetworkType {
+    Ipv4,
+    Ipv6,
+    TorV2,
+    TorV3,
+    I2p,
+    Cjdns,
+    Unknown,
+}
+
+impl NetworkType {
+    /// Every network type, in report order.
+    pub const ALL: [NetworkType; 7] = [
+        NetworkType::Ipv4,
+        NetworkType::Ipv6,
+        NetworkType::TorV2,
+        NetworkType::TorV3,
+        NetworkType::I2p,
+        NetworkType::Cjdns,
+        NetworkType::Unknown,
+    ];
+}
+
+impl fmt::Display for NetworkType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            NetworkType::Ipv4 => "ipv4",
+            NetworkType::Ipv6 => "ipv6",
+            NetworkType::TorV2 => "tor_v2",
+            NetworkType::TorV3 => "tor_v3",
+            NetworkType::I2p => "i2p",
+            NetworkType::Cjdns => "cjdns",
+            NetworkType::Unknown => "unknown",
+        };
+        write!(f, "{name}")
+    }
+}
+
+impl From<&AddrV2> for NetworkType {
+    fn from(addr: &AddrV2) -> Self {
+        match addr {
+            AddrV2::Ipv4(_) => NetworkType::Ipv4,
+            AddrV2::Ipv6(_) => NetworkType::Ipv6,
+            AddrV2::TorV2(_) => NetworkType::TorV2,
+            AddrV2::TorV3(_) => NetworkType::TorV3,
+            AddrV2::I2p(_) => NetworkType::I2p,
+            AddrV2::Cjdns(_) => NetworkType::Cjdns,
+            AddrV2::Unknown(_, _) => NetworkType::Unknown,
+        }
+    }
+}
+
+/// A single node observed during a crawl.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodeObservation {
+    /// Node address.
+    ///
+    /// IP based addresses (IPv4, IPv6, CJDNS) use their standard text form, all others
+    /// are the hex encoded raw address bytes.
+    pub address: String,
+    /// Node port.
+    pub port: u16,
+    /// Network type of the address.
+    pub network: NetworkType,
+    /// Raw service flag bits advertised by the node, none if unknown.
+    pub services: Option<u64>,
+    /// Protocol version advertised by the node, none if unknown.
+    #[serde(default)]
+    pub version: Option<u32>,
+    /// Whether the node accepted a connection.
+    pub listening: bool,
+    /// When the node was observed (Unix timestamp in seconds).
+    pub timestamp: u64,
+}
+
+impl NodeObservation {
+    pub fn from_peer(peer: &Peer, listening: bool, timestamp: u64) -> Self {
+        let services = match peer.services {
+            PeerServices::Known(flags) => Some(flags.to_u64()),
+            PeerServices::Unknown => None,
+        };
+        let version = match peer.version {
+            PeerProtocolVersion::Known(version) => Some(version),
+            PeerProtocolVersion::Unknown => None,
+        };
+
+        Self {
+            address: format_address(&peer.address),
+            port: peer.port,
+            network: NetworkType::from(&peer.address),
+            services,
+            version,
+            listening,
+            timestamp,
+        }
+    }
+}
+
+fn format_address(addr: &AddrV2) -> String {
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    match addr {
+        AddrV2::Ipv4(ip) => ip.to_string(),
+        AddrV2::Ipv6(ip) => ip.to_string(),
+        AddrV2::Cjdns(ip) => ip.to_string(),
+        AddrV2::TorV2(bytes) => hex(bytes),
+        AddrV2::TorV3(bytes) => hex(bytes),
+        AddrV2::I2p(bytes) => hex(bytes),
+        AddrV2::Unknown(_, bytes) => hex(bytes),
+    }
+}
+
+/// Streams node observations to a file as they arrive.
+pub struct NodeWriter {
+    inner: NodeWriterInner,
+}
+
+enum NodeWriterInner {
+    Jsonl(BufWriter<File>),
+    Csv(Box<csv::Writer<File>>),
+}
+
+impl NodeWriter {
+    pub fn create(path: &Path, format: NodeFormat) -> Result<Self, Box<dyn std::error::Error>> {
+        let file = File::create(path)?;
+        let inner = match format {
+            NodeFormat::Jsonl => NodeWriterInner::Jsonl(BufWriter::new(file)),
+            NodeFormat::Csv => NodeWriterInner::Csv(Box::new(csv::Writer::from_writer(file))),
+        };
+
+        Ok(Self { inner })
+    }
+
+    pub fn write(
+        &mut self,
+        observation: &NodeObservation,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        match &mut self.inner {
+            NodeWriterInner::Jsonl(wtr) => {
+                serde_json::to_writer(&mut *wtr, observation)?;
+                wtr.write_all(b"\n")?;
+            }
+            NodeWriterInner::Csv(wtr) => wtr.serialize(observation)?,
+        }
+
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        match &mut self.inner {
+            NodeWriterInner::Jsonl(wtr) => wtr.flush()?,
+            NodeWriterInner::Csv(wtr) => wtr.flush()?,
+        }
+
+        Ok(())
+    }
+}
+
+/// Read all node observations from a file written by a [`NodeWriter`].
+pub fn read_observations(
+    path: &Path,
+    format: NodeFormat,
+) -> Result<Vec<NodeObservation>, Box<dyn std::error::Error>> {
+    let file = File::open(path)?;
+    let observations = match format {
+        NodeFormat::Jsonl => {
+            let mut observations = Vec::new();
+            for line in BufReader::new(file).lines() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                observations.push(serde_json::from_str(&line)?);
+            }
+            observations
+        }
+        NodeFormat::Csv => csv::Reader::from_reader(file)
+            .deserialize()
+            .collect::<Result<Vec<_>, _>>()?,
+    };
+
+    Ok(observations)
+}
@@ -0,0 +1,302 @@
+use crate::node::NetworkType;
+use crate::stats::parse_service_flag;
+use bitcoin::p2p::ServiceFlags;
+use std::collections::HashSet;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Error parsing a [`FeatureQuery`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QueryError {
+    /// The query is not in the `name=expression` form.
+    MissingName(String),
+    /// The expression has an empty term, e.g. a trailing `AND`.
+    EmptyTerm(String),
+    /// A word is not where the expression allows it, e.g. terms joined by `OR`.
+    UnexpectedWord { query: String, word: String },
+    /// The flag name is not a known service flag or `bit_<position>`.
+    UnknownFlag(String),
+    /// More than one query has the same name.
+    DuplicateName(String),
+    /// The name is a service flag, or its counts would share a CSV column with another count.
+    NameClash(String),
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueryError::MissingName(query) => {
+                write!(f, "query must be in the form name=expression: {query}")
+            }
+            QueryError::EmptyTerm(query) => write!(f, "query has an empty term: {query}"),
+            QueryError::UnexpectedWord { query, word } => {
+                write!(f, "unexpected {word}, terms must be joined by AND: {query}")
+            }
+            QueryError::UnknownFlag(flag) => write!(f, "unknown service flag: {flag}"),
+            QueryError::DuplicateName(name) => write!(f, "duplicate query name: {name}"),
+            QueryError::NameClash(name) => {
+                write!(f, "query name clashes with another reported count: {name}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for QueryError {}
+
+/// A single, possibly negated, service flag of a query.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Term {
+    flag: ServiceFlags,
+    negated: bool,
+}
+
+/// A named combination of service flags counted in the census.
+///
+/// Queries are written as `name=expression` where the expression is service flags joined by
+/// `AND`, each optionally prefixed with `NOT`. The keywords are case-insensitive. For example
+/// `pruned_v2=network_limited AND p2p_v2` or `witness_no_bloom=witness AND NOT bloom`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FeatureQuery {
+    /// Name the query is reported under.
+    pub name: String,
+    terms: Vec<Term>,
+}
+
+impl FeatureQuery {
+    /// Check if a node's service flags satisfy every term of the query.
+    pub fn matches(&self, flags: ServiceFlags) -> bool {
+        self.terms
+            .iter()
+            .all(|term| flags.has(term.flag) != term.negated)
+    }
+}
+
+impl FromStr for FeatureQuery {
+    type Err = QueryError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, expression) = s
+            .split_once('=')
+            .map(|(name, expression)| (name.trim(), expression.trim()))
+            .filter(|(name, _)| !name.is_empty())
+            .ok_or_else(|| QueryError::MissingName(s.to_string()))?;
+
+        let is_word = |word: &str, keyword: &str| word.eq_ignore_ascii_case(keyword);
+        let mut words = expression.split_whitespace().peekable();
+        let mut terms = Vec::new();
+        loop {
+            let negated = words.next_if(|word| is_word(word, "NOT")).is_some();
+            let flag = match words.next() {
+                Some(word) if is_word(word, "NOT") => {
+                    return Err(QueryError::UnexpectedWord {
+                        query: s.to_string(),
+                        word: word.to_string(),
+                    })
+                }
+                Some(word) if !is_word(word, "AND") => word,
+                _ => return Err(QueryError::EmptyTerm(s.to_string())),
+            };
+            let flag = parse_service_flag(flag)
+                .ok_or_else(|| QueryError::UnknownFlag(flag.to_string()))?;
+            terms.push(Term { flag, negated });
+
+            match words.next() {
+                None => break,
+                Some(word) if is_word(word, "AND") => {}
+                Some(word) => {
+                    return Err(QueryError::UnexpectedWord {
+                        query: s.to_string(),
+                        word: word.to_string(),
+                    })
+                }
+            }
+        }
+
+        Ok(Self {
+            name: name.to_string(),
+            terms,
+        })
+    }
+}
+
+/// Counts every report has, which are never shadowed by a query.
+const REPORTED_NAMES: [&str; 7] = [
+    "timestamp",
+    "duration_seconds",
+    "total_nodes",
+    "total_contacted",
+    "v2_transport",
+    "compact_filters",
+    "v2_and_filters",
+];
+
+/// Counts of every connection type, reported in CSV as `<network>_<name>`.
+const CONNECTION_TYPE_NAMES: [&str; 4] = ["total", "v2", "filters", "v2_and_filters"];
+
+/// CSV columns of a count, its percentage and the bounds of the percentage's interval.
+fn csv_columns(name: &str) -> [String; 4] {
+    ["", "_pct", "_pct_low", "_pct_high"].map(|suffix| format!("{name}{suffix}"))
+}
+
+/// CSV columns of a count per connection type.
+fn connection_type_columns(name: &str) -> impl Iterator<Item = String> + '_ {
+    NetworkType::ALL
+        .into_iter()
+        .flat_map(move |network| csv_columns(&format!("{network}_{name}")))
+}
+
+/// Check that every query has its own name and CSV columns, since clashing counts would be merged
+/// or shadowed. Names of service flags are rejected too, as metrics resolve to the flag.
+pub fn check_names(queries: &[FeatureQuery]) -> Result<(), QueryError> {
+    let mut columns: HashSet<String> = REPORTED_NAMES
+        .into_iter()
+        .flat_map(csv_columns)
+        .chain(
+            CONNECTION_TYPE_NAMES
+                .into_iter()
+                .flat_map(connection_type_columns),
+        )
+        .collect();
+    let mut names = HashSet::new();
+    for query in queries {
+        let name = query.name.as_str();
+        if !names.insert(name) {
+            return Err(QueryError::DuplicateName(name.to_string()));
+        }
+        let clashes = parse_service_flag(name).is_some()
+            || !csv_columns(name)
+                .into_iter()
+                .chain(connection_type_columns(name))
+                .all(|column| columns.insert(column));
+        if clashes {
+            return Err(QueryError::NameClash(name.to_string()));
+        }
+    }
+
+    Ok(())
+}
+
+/// Read queries from a file, one per line. Blank lines and lines starting with `#` are skipped.
+/// Query names must not clash, see [`check_names`].
+pub fn read_queries(path: &Path) -> Result<Vec<FeatureQuery>, Box<dyn std::error::Error>> {
+    let mut queries = Vec::new();
+    for line in std::fs::read_to_string(path)?.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        queries.push(line.parse()?);
+    }
+    check_names(&queries)?;
+
+    Ok(queries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_and_match() {
+        let query: FeatureQuery = " pruned_v2 = pruned AND p2p_v2 AND NOT bloom "
+            .parse()
+            .unwrap();
+        assert_eq!(query.name, "pruned_v2");
+        let pruned_v2 = ServiceFlags::NETWORK_LIMITED | ServiceFlags::P2P_V2;
+        assert!(query.matches(pruned_v2));
+        assert!(query.matches(pruned_v2 | ServiceFlags::WITNESS));
+        assert!(!query.matches(pruned_v2 | ServiceFlags::BLOOM));
+        assert!(!query.matches(ServiceFlags::P2P_V2));
+    }
+
+    #[test]
+    fn keywords_in_any_case() {
+        let upper: FeatureQuery = "x=v2 AND NOT filters".parse().unwrap();
+        let lower: FeatureQuery = "x=v2 and not filters".parse().unwrap();
+        let mixed: FeatureQuery = "x=v2 And Not filters".parse().unwrap();
+        assert_eq!(upper, lower);
+        assert_eq!(upper, mixed);
+    }
+
+    #[test]
+    fn parse_errors() {
+        let error = |query: &str| query.parse::<FeatureQuery>().unwrap_err();
+        assert_eq!(error("v2"), QueryError::MissingName("v2".to_string()));
+        assert_eq!(error("=v2"), QueryError::MissingName("=v2".to_string()));
+        assert_eq!(error("x="), QueryError::EmptyTerm("x=".to_string()));
+        assert_eq!(
+            error("x=v2 AND"),
+            QueryError::EmptyTerm("x=v2 AND".to_string())
+        );
+        assert_eq!(
+            error("x=v2 AND AND bloom"),
+            QueryError::EmptyTerm("x=v2 AND AND bloom".to_string())
+        );
+        assert_eq!(
+            error("x=v2 OR bloom"),
+            QueryError::UnexpectedWord {
+                query: "x=v2 OR bloom".to_string(),
+                word: "OR".to_string(),
+            }
+        );
+        assert_eq!(
+            error("x=NOT NOT bloom"),
+            QueryError::UnexpectedWord {
+                query: "x=NOT NOT bloom".to_string(),
+                word: "NOT".to_string(),
+            }
+        );
+        assert_eq!(
+            error("x=v2 AND nonsense"),
+            QueryError::UnknownFlag("nonsense".to_string())
+        );
+    }
+
+    #[test]
+    fn unique_names() {
+        let queries: Vec<FeatureQuery> = ["a=v2", "b=bloom", "a=pruned"]
+            .iter()
+            .map(|query| query.parse().unwrap())
+            .collect();
+        assert!(check_names(&queries[..2]).is_ok());
+        assert_eq!(
+            check_names(&queries),
+            Err(QueryError::DuplicateName("a".to_string()))
+        );
+    }
+
+    #[test]
+    fn clashing_names() {
+        let clash = |name: &str| {
+            let queries = [format!("{name}=witness").parse().unwrap()];
+            check_names(&queries) == Err(QueryError::NameClash(name.to_string()))
+        };
+        for name in [
+            "total_nodes",
+            "v2_transport",
+            "compact_filters",
+            "v2_and_filters",
+            "total",
+            "v2",
+            "filters",
+            "witness",
+            "pruned",
+            "BLOOM",
+            "bit_30",
+            "v2_transport_pct",
+        ] {
+            assert!(clash(name), "{name} should clash");
+        }
+        assert!(!clash("witness_v2"));
+
+        let queries: Vec<FeatureQuery> = ["a=witness", "a_pct=bloom"]
+            .iter()
+            .map(|query| query.parse().unwrap())
+            .collect();
+        assert_eq!(
+            check_names(&queries),
+            Err(QueryError::NameClash("a_pct".to_string()))
+        );
+    }
+}
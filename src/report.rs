@@ -1,8 +1,15 @@
-use crate::stats::{FeatureStats, NodeStats};
+use crate::network::Network;
+use crate::node::NetworkType;
+use crate::stats::{ConfidenceInterval, ConnectionTypeFeatures, FeatureStats, NodeStats};
 use clap::ValueEnum;
+use fs4::fs_std::FileExt;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fmt;
-use std::path::PathBuf;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::time::{SystemTime, UNIX_EPOCH};
 
 #[derive(Debug, Clone, Copy, ValueEnum)]
@@ -10,6 +17,48 @@ pub enum OutputFormat {
     Json,
     Jsonl,
     Csv,
+    /// OpenMetrics text, e.g. for node_exporter's textfile collector.
+    Prometheus,
+}
+
+/// Configuration of the crawl which produced a report.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct CrawlConfig {
+    /// Resolved seed nodes, tried in order until one connects.
+    pub seeds: Vec<SocketAddr>,
+    /// Whether every seed was crawled rather than only the first which connects.
+    #[serde(default)]
+    pub crawl_all_seeds: bool,
+    /// Maximum concurrent connections.
+    pub concurrent: usize,
+    /// Transport policy of the crawler's connections.
+    pub transport_policy: String,
+    /// Protocol version advertised by the crawler.
+    pub protocol_version: u32,
+    /// User agent advertised by the crawler.
+    pub user_agent: String,
+    /// Identifier of the host which ran the crawl.
+    pub host: Option<String>,
+    /// Crawl time limit in seconds.
+    #[serde(default)]
+    pub max_duration_seconds: Option<u64>,
+    /// Crawl listening node limit.
+    #[serde(default)]
+    pub max_nodes: Option<usize>,
+}
+
+/// Why a crawl stopped before exhausting the network.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum StopReason {
+    /// The process was interrupted by a signal.
+    Interrupted,
+    /// The crawl hit its time limit.
+    MaxDuration,
+    /// The crawl hit its listening node limit.
+    MaxNodes,
 }
 
 /// A census report containing statistics from a network crawl.
@@ -20,6 +69,9 @@ pub struct CensusReport {
     pub timestamp: u64,
     /// Duration of the census in seconds.
     pub duration_seconds: u64,
+    /// Bitcoin network the census was taken of.
+    #[serde(default)]
+    pub network: Network,
     /// Total number of nodes contacted (listening + non-listening).
     #[serde(default)]
     pub total_contacted: usize,
@@ -27,24 +79,43 @@ pub struct CensusReport {
     pub stats: FeatureStats,
     /// Version of the census tool.
     pub census_version: String,
+    /// Crawl configuration, none if the report was not produced by a crawl.
+    #[serde(default)]
+    pub config: Option<CrawlConfig>,
+    /// Interim snapshot taken while the crawl was still running.
+    #[serde(default)]
+    pub partial: bool,
+    /// Why the crawl stopped early, none if it ran to completion.
+    #[serde(default)]
+    pub stop_reason: Option<StopReason>,
 }
 
 impl CensusReport {
-    pub fn from_stats(node_stats: &NodeStats) -> Self {
-        let timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
+    pub fn from_stats(node_stats: &NodeStats, network: Network) -> Self {
+        let timestamp = now();
+        let mut stats = node_stats.features.clone();
+        stats.update_confidence_intervals();
 
         Self {
             timestamp,
             duration_seconds: node_stats.duration(),
+            network,
             total_contacted: node_stats.total_contacted(),
-            stats: node_stats.features.clone(),
+            stats,
             census_version: env!("CARGO_PKG_VERSION").to_string(),
+            config: None,
+            partial: false,
+            stop_reason: None,
         }
     }
 
+    /// Record the crawl configuration which produced the report.
+    pub fn with_config(mut self, config: CrawlConfig) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    /// Write the report to a file, replacing it atomically, or to stdout if there is none.
     pub fn write(
         &self,
         format: OutputFormat,
@@ -53,11 +124,12 @@ impl CensusReport {
         let content = match format {
             OutputFormat::Json => serde_json::to_string_pretty(self)?,
             OutputFormat::Jsonl => format!("{}\n", serde_json::to_string(self)?),
-            OutputFormat::Csv => self.format_csv()?,
+            OutputFormat::Csv => self.format_csv(true, true)?,
+            OutputFormat::Prometheus => self.format_prometheus()?,
         };
 
         if let Some(path) = output {
-            std::fs::write(path, content)?;
+            write_atomic(&path, &content)?;
         } else {
             print!("{content}");
         }
@@ -65,266 +137,480 @@ impl CensusReport {
         Ok(())
     }
 
-    fn format_csv(&self) -> Result<String, Box<dyn std::error::Error>> {
+    /// Write the report as a single JSON line.
+    pub fn write_jsonl<W: Write>(&self, writer: &mut W) -> Result<(), Box<dyn std::error::Error>> {
+        serde_json::to_writer(&mut *writer, self)?;
+        writer.write_all(b"\n")?;
+        writer.flush()?;
+
+        Ok(())
+    }
+
+    /// Append the report to a history file in a JSONL or CSV format.
+    ///
+    /// An exclusive lock on the history serializes appends from multiple runners. Every existing
+    /// JSONL line must parse as a report, or the existing CSV header must match the report's
+    /// columns, before anything is written. The report is then appended to the file in place, so
+    /// its mode, owner and open handles are kept, and synced to disk. A CSV header is only written
+    /// when the history is created.
+    pub fn append(
+        &self,
+        format: OutputFormat,
+        path: &Path,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut file = lock_history(
+            OpenOptions::new().read(true).append(true).create(true),
+            path,
+        )?;
+        let mut history = String::new();
+        file.read_to_string(&mut history)?;
+
+        let mut record = match format {
+            OutputFormat::Jsonl => {
+                for (number, line) in history.lines().enumerate() {
+                    if !line.trim().is_empty() {
+                        serde_json::from_str::<CensusReport>(line).map_err(|e| {
+                            format!(
+                                "{} line {} is not a census report: {e}",
+                                path.display(),
+                                number + 1
+                            )
+                        })?;
+                    }
+                }
+                format!("{}\n", serde_json::to_string(self)?)
+            }
+            OutputFormat::Csv => match history.lines().next() {
+                None => self.format_csv(true, true)?,
+                Some(existing) => {
+                    // Histories from before the confidence intervals are appended to without them.
+                    let mut intervals = None;
+                    for with_intervals in [true, false] {
+                        let header = self.format_csv(true, with_intervals)?;
+                        if header.lines().next() == Some(existing) {
+                            intervals = Some(with_intervals);
+                            break;
+                        }
+                    }
+                    let intervals = intervals.ok_or_else(|| {
+                        format!(
+                            "{} has different CSV columns than the report",
+                            path.display()
+                        )
+                    })?;
+                    self.format_csv(false, intervals)?
+                }
+            },
+            OutputFormat::Json | OutputFormat::Prometheus => {
+                return Err("only jsonl and csv reports can be appended".into());
+            }
+        };
+
+        if !history.is_empty() && !history.ends_with('\n') {
+            record.insert(0, '\n');
+        }
+        file.write_all(record.as_bytes())?;
+        file.sync_all()?;
+
+        Ok(())
+    }
+
+    /// Format the report as a CSV row, optionally preceded by the header.
+    ///
+    /// Without intervals, the columns are those of reports before the confidence intervals, so
+    /// older histories can still be appended to.
+    fn format_csv(
+        &self,
+        header: bool,
+        intervals: bool,
+    ) -> Result<String, Box<dyn std::error::Error>> {
         let mut wtr = csv::Writer::from_writer(vec![]);
+        let CsvColumns {
+            mut columns,
+            intervals: interval_columns,
+        } = self.csv_columns();
+        if intervals {
+            columns.extend(interval_columns);
+        }
+        let (headers, values): (Vec<String>, Vec<String>) = columns.into_iter().unzip();
+        if header {
+            wtr.write_record(headers)?;
+        }
+        wtr.write_record(values)?;
 
-        // Write headers.
-        wtr.write_record([
-            "timestamp",
-            "duration_seconds",
-            "total_nodes",
-            "total_contacted",
-            "v2_transport",
-            "v2_transport_pct",
-            "compact_filters",
-            "compact_filters_pct",
-            "v2_and_filters",
-            "v2_and_filters_pct",
-            // IPv4 stats
-            "ipv4_total",
-            "ipv4_total_pct",
-            "ipv4_v2",
-            "ipv4_v2_pct",
-            "ipv4_filters",
-            "ipv4_filters_pct",
-            "ipv4_v2_and_filters",
-            "ipv4_v2_and_filters_pct",
-            // IPv6 stats
-            "ipv6_total",
-            "ipv6_total_pct",
-            "ipv6_v2",
-            "ipv6_v2_pct",
-            "ipv6_filters",
-            "ipv6_filters_pct",
-            "ipv6_v2_and_filters",
-            "ipv6_v2_and_filters_pct",
-            // Tor v2 stats
-            "tor_v2_total",
-            "tor_v2_total_pct",
-            "tor_v2_v2",
-            "tor_v2_v2_pct",
-            "tor_v2_filters",
-            "tor_v2_filters_pct",
-            "tor_v2_v2_and_filters",
-            "tor_v2_v2_and_filters_pct",
-            // Tor v3 stats
-            "tor_v3_total",
-            "tor_v3_total_pct",
-            "tor_v3_v2",
-            "tor_v3_v2_pct",
-            "tor_v3_filters",
-            "tor_v3_filters_pct",
-            "tor_v3_v2_and_filters",
-            "tor_v3_v2_and_filters_pct",
-            // I2P stats
-            "i2p_total",
-            "i2p_total_pct",
-            "i2p_v2",
-            "i2p_v2_pct",
-            "i2p_filters",
-            "i2p_filters_pct",
-            "i2p_v2_and_filters",
-            "i2p_v2_and_filters_pct",
-            // CJDNS stats
-            "cjdns_total",
-            "cjdns_total_pct",
-            "cjdns_v2",
-            "cjdns_v2_pct",
-            "cjdns_filters",
-            "cjdns_filters_pct",
-            "cjdns_v2_and_filters",
-            "cjdns_v2_and_filters_pct",
-            // Unknown stats
-            "unknown_total",
-            "unknown_total_pct",
-            "unknown_v2",
-            "unknown_v2_pct",
-            "unknown_filters",
-            "unknown_filters_pct",
-            "unknown_v2_and_filters",
-            "unknown_v2_and_filters_pct",
-        ])?;
-
-        // Write data.
+        Ok(String::from_utf8(wtr.into_inner()?)?)
+    }
+
+    /// CSV headers and values of the report, overall and per connection type, followed by the
+    /// configured feature combinations.
+    fn csv_columns(&self) -> CsvColumns {
         let stats = &self.stats;
         let conn_types = &stats.connection_types;
-        wtr.write_record(&[
-            self.timestamp.to_string(),
-            self.duration_seconds.to_string(),
-            stats.total_nodes.to_string(),
-            self.total_contacted.to_string(),
-            stats.v2_transport.to_string(),
-            format!("{:.2}", stats.percentage(stats.v2_transport)),
-            stats.compact_filters.to_string(),
-            format!("{:.2}", stats.percentage(stats.compact_filters)),
-            stats.v2_and_filters.to_string(),
-            format!("{:.2}", stats.percentage(stats.v2_and_filters)),
-            // IPv4 stats
-            conn_types.ipv4.total_nodes.to_string(),
-            format!(
-                "{:.2}",
-                conn_types.connection_percentage(conn_types.ipv4.total_nodes)
-            ),
-            conn_types.ipv4.v2_transport.to_string(),
-            format!(
-                "{:.2}",
-                conn_types.ipv4.percentage(conn_types.ipv4.v2_transport)
-            ),
-            conn_types.ipv4.compact_filters.to_string(),
-            format!(
-                "{:.2}",
-                conn_types.ipv4.percentage(conn_types.ipv4.compact_filters)
-            ),
-            conn_types.ipv4.v2_and_filters.to_string(),
-            format!(
-                "{:.2}",
-                conn_types.ipv4.percentage(conn_types.ipv4.v2_and_filters)
-            ),
-            // IPv6 stats
-            conn_types.ipv6.total_nodes.to_string(),
-            format!(
-                "{:.2}",
-                conn_types.connection_percentage(conn_types.ipv6.total_nodes)
-            ),
-            conn_types.ipv6.v2_transport.to_string(),
-            format!(
-                "{:.2}",
-                conn_types.ipv6.percentage(conn_types.ipv6.v2_transport)
-            ),
-            conn_types.ipv6.compact_filters.to_string(),
-            format!(
-                "{:.2}",
-                conn_types.ipv6.percentage(conn_types.ipv6.compact_filters)
-            ),
-            conn_types.ipv6.v2_and_filters.to_string(),
-            format!(
-                "{:.2}",
-                conn_types.ipv6.percentage(conn_types.ipv6.v2_and_filters)
-            ),
-            // Tor v2 stats
-            conn_types.tor_v2.total_nodes.to_string(),
-            format!(
-                "{:.2}",
-                conn_types.connection_percentage(conn_types.tor_v2.total_nodes)
-            ),
-            conn_types.tor_v2.v2_transport.to_string(),
-            format!(
-                "{:.2}",
-                conn_types.tor_v2.percentage(conn_types.tor_v2.v2_transport)
-            ),
-            conn_types.tor_v2.compact_filters.to_string(),
-            format!(
-                "{:.2}",
-                conn_types
-                    .tor_v2
-                    .percentage(conn_types.tor_v2.compact_filters)
-            ),
-            conn_types.tor_v2.v2_and_filters.to_string(),
-            format!(
-                "{:.2}",
-                conn_types
-                    .tor_v2
-                    .percentage(conn_types.tor_v2.v2_and_filters)
-            ),
-            // Tor v3 stats
-            conn_types.tor_v3.total_nodes.to_string(),
-            format!(
-                "{:.2}",
-                conn_types.connection_percentage(conn_types.tor_v3.total_nodes)
-            ),
-            conn_types.tor_v3.v2_transport.to_string(),
-            format!(
-                "{:.2}",
-                conn_types.tor_v3.percentage(conn_types.tor_v3.v2_transport)
-            ),
-            conn_types.tor_v3.compact_filters.to_string(),
-            format!(
-                "{:.2}",
-                conn_types
-                    .tor_v3
-                    .percentage(conn_types.tor_v3.compact_filters)
-            ),
-            conn_types.tor_v3.v2_and_filters.to_string(),
-            format!(
-                "{:.2}",
-                conn_types
-                    .tor_v3
-                    .percentage(conn_types.tor_v3.v2_and_filters)
-            ),
-            // I2P stats
-            conn_types.i2p.total_nodes.to_string(),
-            format!(
-                "{:.2}",
-                conn_types.connection_percentage(conn_types.i2p.total_nodes)
-            ),
-            conn_types.i2p.v2_transport.to_string(),
-            format!(
-                "{:.2}",
-                conn_types.i2p.percentage(conn_types.i2p.v2_transport)
-            ),
-            conn_types.i2p.compact_filters.to_string(),
-            format!(
-                "{:.2}",
-                conn_types.i2p.percentage(conn_types.i2p.compact_filters)
-            ),
-            conn_types.i2p.v2_and_filters.to_string(),
-            format!(
-                "{:.2}",
-                conn_types.i2p.percentage(conn_types.i2p.v2_and_filters)
-            ),
-            // CJDNS stats
-            conn_types.cjdns.total_nodes.to_string(),
-            format!(
-                "{:.2}",
-                conn_types.connection_percentage(conn_types.cjdns.total_nodes)
-            ),
-            conn_types.cjdns.v2_transport.to_string(),
-            format!(
-                "{:.2}",
-                conn_types.cjdns.percentage(conn_types.cjdns.v2_transport)
-            ),
-            conn_types.cjdns.compact_filters.to_string(),
-            format!(
-                "{:.2}",
-                conn_types
-                    .cjdns
-                    .percentage(conn_types.cjdns.compact_filters)
-            ),
-            conn_types.cjdns.v2_and_filters.to_string(),
-            format!(
-                "{:.2}",
-                conn_types.cjdns.percentage(conn_types.cjdns.v2_and_filters)
-            ),
-            // Unknown stats
-            conn_types.unknown.total_nodes.to_string(),
-            format!(
-                "{:.2}",
-                conn_types.connection_percentage(conn_types.unknown.total_nodes)
-            ),
-            conn_types.unknown.v2_transport.to_string(),
-            format!(
-                "{:.2}",
-                conn_types
-                    .unknown
-                    .percentage(conn_types.unknown.v2_transport)
-            ),
-            conn_types.unknown.compact_filters.to_string(),
-            format!(
-                "{:.2}",
-                conn_types
-                    .unknown
-                    .percentage(conn_types.unknown.compact_filters)
-            ),
-            conn_types.unknown.v2_and_filters.to_string(),
-            format!(
-                "{:.2}",
-                conn_types
-                    .unknown
-                    .percentage(conn_types.unknown.v2_and_filters)
+        let mut columns = CsvColumns {
+            columns: vec![
+                ("timestamp".to_string(), self.timestamp.to_string()),
+                (
+                    "duration_seconds".to_string(),
+                    self.duration_seconds.to_string(),
+                ),
+                ("total_nodes".to_string(), stats.total_nodes.to_string()),
+                (
+                    "total_contacted".to_string(),
+                    self.total_contacted.to_string(),
+                ),
+            ],
+            intervals: Vec::new(),
+        };
+
+        for (name, count) in [
+            ("v2_transport", stats.v2_transport),
+            ("compact_filters", stats.compact_filters),
+            ("v2_and_filters", stats.v2_and_filters),
+        ] {
+            columns.count(name, count, stats.percentage(count), stats.interval(count));
+        }
+        for network in NetworkType::ALL {
+            let conn_type = conn_types.get(network);
+            columns.count(
+                &format!("{network}_total"),
+                conn_type.total_nodes,
+                conn_types.connection_percentage(conn_type.total_nodes),
+                conn_types.connection_interval(conn_type.total_nodes),
+            );
+            for (name, count) in [
+                ("v2", conn_type.v2_transport),
+                ("filters", conn_type.compact_filters),
+                ("v2_and_filters", conn_type.v2_and_filters),
+            ] {
+                columns.count(
+                    &format!("{network}_{name}"),
+                    count,
+                    conn_type.percentage(count),
+                    conn_type.interval(count),
+                );
+            }
+        }
+
+        for (name, count) in &stats.combinations {
+            columns.count(
+                name,
+                *count,
+                stats.percentage(*count),
+                stats.interval(*count),
+            );
+            for network in NetworkType::ALL {
+                let conn_type = conn_types.get(network);
+                let count = conn_type.combinations.get(name).copied().unwrap_or(0);
+                columns.count(
+                    &format!("{network}_{name}"),
+                    count,
+                    conn_type.percentage(count),
+                    conn_type.interval(count),
+                );
+            }
+        }
+
+        columns
+    }
+
+    fn format_prometheus(&self) -> Result<String, fmt::Error> {
+        let mut out = String::new();
+        self.write_openmetrics("census", &mut out)?;
+        out.push_str("# EOF\n");
+
+        Ok(out)
+    }
+
+    /// Write the report as OpenMetrics gauges named with the prefix, labelled by connection type.
+    ///
+    /// The trailing `# EOF` is left to the caller, so several reports can be exposed together.
+    pub fn write_openmetrics<W: fmt::Write>(&self, prefix: &str, out: &mut W) -> fmt::Result {
+        let stats = &self.stats;
+        let per_network = |metric: &dyn Fn(&ConnectionTypeFeatures) -> Vec<(String, usize)>| {
+            NetworkType::ALL
+                .into_iter()
+                .flat_map(|network| {
+                    metric(stats.connection_types.get(network)).into_iter().map(
+                        move |(labels, count)| {
+                            let network = label("network", network);
+                            let labels = if labels.is_empty() {
+                                network
+                            } else {
+                                format!("{network},{labels}")
+                            };
+                            (labels, count as u64)
+                        },
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+
+        write_gauge(
+            out,
+            &format!("{prefix}_timestamp_seconds"),
+            "When the census was taken.",
+            &[(String::new(), self.timestamp)],
+        )?;
+        write_gauge(
+            out,
+            &format!("{prefix}_duration_seconds"),
+            "Duration of the census.",
+            &[(String::new(), self.duration_seconds)],
+        )?;
+        write_gauge(
+            out,
+            &format!("{prefix}_contacted_nodes"),
+            "Nodes contacted, listening or not.",
+            &[(String::new(), self.total_contacted as u64)],
+        )?;
+        write_gauge(
+            out,
+            &format!("{prefix}_listening_nodes"),
+            "Listening nodes by connection type.",
+            &per_network(&|conn_type| vec![(String::new(), conn_type.total_nodes)]),
+        )?;
+        write_gauge(
+            out,
+            &format!("{prefix}_nodes"),
+            "Listening nodes supporting a feature by connection type.",
+            &per_network(&|conn_type| {
+                [
+                    ("v2_transport", conn_type.v2_transport),
+                    ("compact_filters", conn_type.compact_filters),
+                    ("v2_and_filters", conn_type.v2_and_filters),
+                ]
+                .into_iter()
+                .map(|(feature, count)| (label("feature", feature), count))
+                .collect()
+            }),
+        )?;
+        write_gauge(
+            out,
+            &format!("{prefix}_service_flag_nodes"),
+            "Listening nodes advertising a service flag by connection type.",
+            &per_network(&|conn_type| labelled("flag", &conn_type.service_flags)),
+        )?;
+        write_gauge(
+            out,
+            &format!("{prefix}_combination_nodes"),
+            "Listening nodes matching a feature combination by connection type.",
+            &per_network(&|conn_type| labelled("combination", &conn_type.combinations)),
+        )?;
+        write_gauge(
+            out,
+            &format!("{prefix}_protocol_version_nodes"),
+            "Listening nodes advertising a protocol version by connection type.",
+            &per_network(&|conn_type| labelled("version", &conn_type.protocol_versions)),
+        )
+    }
+}
+
+/// CSV headers and values of a report.
+struct CsvColumns {
+    columns: Vec<(String, String)>,
+    /// Bounds of the confidence intervals, which go after all other columns so the layout of
+    /// reports before them is kept.
+    intervals: Vec<(String, String)>,
+}
+
+impl CsvColumns {
+    /// Add a count and its percentage, and the bounds of the percentage's 95% confidence
+    /// interval as `<name>_pct_low` and `<name>_pct_high`.
+    fn count(&mut self, name: &str, count: usize, percentage: f64, interval: ConfidenceInterval) {
+        self.columns.push((name.to_string(), count.to_string()));
+        self.columns
+            .push((format!("{name}_pct"), format!("{percentage:.2}")));
+        self.intervals
+            .push((format!("{name}_pct_low"), format!("{:.2}", interval.low)));
+        self.intervals
+            .push((format!("{name}_pct_high"), format!("{:.2}", interval.high)));
+    }
+}
+
+/// Replace the contents of a file through a temporary file, so readers such as node_exporter's
+/// textfile collector never see a partial write.
+fn write_atomic(path: &Path, contents: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = sibling(path, ".tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    drop(file);
+    std::fs::rename(&tmp, path)?;
+
+    Ok(())
+}
+
+/// Current Unix timestamp in seconds.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// UTC date and time of a timestamp.
+pub(crate) fn date(timestamp: u64) -> String {
+    chrono::DateTime::from_timestamp(timestamp as i64, 0).map_or_else(
+        || timestamp.to_string(),
+        |time| time.format("%Y-%m-%d %H:%M UTC").to_string(),
+    )
+}
+
+/// Path next to another, named with a suffix.
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut sibling = path.as_os_str().to_owned();
+    sibling.push(suffix);
+    PathBuf::from(sibling)
+}
+
+/// Format an OpenMetrics label, escaping the value.
+fn label(name: &str, value: impl fmt::Display) -> String {
+    let value = value
+        .to_string()
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n");
+    format!("{name}=\"{value}\"")
+}
+
+/// Samples of a count map, labelled by key.
+fn labelled(name: &str, counts: &BTreeMap<String, usize>) -> Vec<(String, usize)> {
+    counts
+        .iter()
+        .map(|(key, count)| (label(name, key), *count))
+        .collect()
+}
+
+/// Write an OpenMetrics gauge family, each sample being its labels and value.
+fn write_gauge<W: fmt::Write>(
+    out: &mut W,
+    name: &str,
+    help: &str,
+    samples: &[(String, u64)],
+) -> fmt::Result {
+    writeln!(out, "# TYPE {name} gauge")?;
+    writeln!(out, "# HELP {name} {help}")?;
+    for (labels, value) in samples {
+        if labels.is_empty() {
+            writeln!(out, "{name} {value}")?;
+        } else {
+            writeln!(out, "{name}{{{labels}}} {value}")?;
+        }
+    }
+    Ok(())
+}
+
+/// Parse a history of reports, one JSON report per line. Blank lines are skipped.
+pub fn parse_history(history: &str) -> Result<Vec<CensusReport>, serde_json::Error> {
+    history
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(serde_json::from_str)
+        .collect()
+}
+
+/// Read a history file of reports written as JSONL.
+pub fn read_history(path: &Path) -> Result<Vec<CensusReport>, Box<dyn std::error::Error>> {
+    Ok(parse_history(&std::fs::read_to_string(path)?)?)
+}
+
+/// Read a report from a JSON file, or the last report of a JSONL history.
+pub fn read_report(path: &Path) -> Result<CensusReport, Box<dyn std::error::Error>> {
+    let content = std::fs::read_to_string(path)?;
+    if let Ok(report) = serde_json::from_str(&content) {
+        return Ok(report);
+    }
+
+    parse_history(&content)?
+        .pop()
+        .ok_or_else(|| format!("{} has no census report", path.display()).into())
+}
+
+/// Rewrite a JSONL history file, holding the append lock so no concurrent append is lost.
+///
+/// The original history is first copied to `<path>.bak`, since a rewrite may drop reports, and an
+/// existing backup is never overwritten. The rewritten history then replaces the original through
+/// a temporary file, so readers which do not take the lock never see a partial history.
+pub fn rewrite_history(
+    path: &Path,
+    rewrite: impl FnOnce(Vec<CensusReport>) -> Vec<CensusReport>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut file = lock_history(OpenOptions::new().read(true), path)?;
+    let mut history = String::new();
+    file.read_to_string(&mut history)?;
+
+    let backup = sibling(path, ".bak");
+    let mut backup_file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&backup)
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::AlreadyExists => format!(
+                "backup {} already exists, move it away to rewrite {}",
+                backup.display(),
+                path.display()
             ),
-        ])?;
+            _ => format!("cannot create backup {}: {e}", backup.display()),
+        })?;
+    backup_file.write_all(history.as_bytes())?;
+    backup_file.sync_all()?;
 
-        Ok(String::from_utf8(wtr.into_inner()?)?)
+    let mut rewritten = String::new();
+    for report in rewrite(parse_history(&history)?) {
+        rewritten.push_str(&serde_json::to_string(&report)?);
+        rewritten.push('\n');
+    }
+    write_atomic(path, &rewritten)?;
+    std::fs::set_permissions(path, file.metadata()?.permissions())?;
+
+    Ok(())
+}
+
+/// Open a history file and take its exclusive lock, opening it again if a rewrite replaced the
+/// file while waiting for the lock.
+fn lock_history(options: &OpenOptions, path: &Path) -> std::io::Result<File> {
+    loop {
+        let file = options.open(path)?;
+        file.lock_exclusive()?;
+        if is_current(&file, path)? {
+            return Ok(file);
+        }
+    }
+}
+
+/// Whether an open file is still the one at the path.
+#[cfg(unix)]
+fn is_current(file: &File, path: &Path) -> std::io::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+
+    let opened = file.metadata()?;
+    match std::fs::metadata(path) {
+        Ok(current) => Ok(opened.dev() == current.dev() && opened.ino() == current.ino()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Whether an open file is still the one at the path, assumed where files cannot be compared.
+#[cfg(not(unix))]
+fn is_current(_file: &File, _path: &Path) -> std::io::Result<bool> {
+    Ok(true)
+}
+
+/// A count with its percentage and the percentage's 95% confidence interval.
+struct Share {
+    count: usize,
+    percentage: f64,
+    interval: ConfidenceInterval,
+}
+
+impl fmt::Display for Share {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} ({:.1}% [{:.1}-{:.1}])",
+            self.count, self.percentage, self.interval.low, self.interval.high
+        )
     }
 }
 
@@ -332,27 +618,247 @@ impl fmt::Display for CensusReport {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let stats = &self.stats;
         let conn_types = &stats.connection_types;
+        let share = |count| Share {
+            count,
+            percentage: stats.percentage(count),
+            interval: stats.interval(count),
+        };
+        let connection_share = |count| Share {
+            count,
+            percentage: conn_types.connection_percentage(count),
+            interval: conn_types.connection_interval(count),
+        };
         write!(
             f,
-            "nodes: {} out of {} contacted | v2: {} ({:.1}%) | filters: {} ({:.1}%) | v2 & filters: {} ({:.1}%) | ipv4: {} ({:.1}%) | ipv6: {} ({:.1}%) | tor: {} ({:.1}%) | i2p: {} ({:.1}%) | cjdns: {} ({:.1}%)",
+            "nodes: {} out of {} contacted | v2: {} | filters: {} | v2 & filters: {} | ipv4: {} | ipv6: {} | tor: {} | i2p: {} | cjdns: {}",
             stats.total_nodes,
             self.total_contacted,
-            stats.v2_transport,
-            stats.percentage(stats.v2_transport),
-            stats.compact_filters,
-            stats.percentage(stats.compact_filters),
-            stats.v2_and_filters,
-            stats.percentage(stats.v2_and_filters),
-            conn_types.ipv4.total_nodes,
-            conn_types.connection_percentage(conn_types.ipv4.total_nodes),
-            conn_types.ipv6.total_nodes,
-            conn_types.connection_percentage(conn_types.ipv6.total_nodes),
-            conn_types.tor_v2.total_nodes + conn_types.tor_v3.total_nodes,
-            conn_types.connection_percentage(conn_types.tor_v2.total_nodes + conn_types.tor_v3.total_nodes),
-            conn_types.i2p.total_nodes,
-            conn_types.connection_percentage(conn_types.i2p.total_nodes),
-            conn_types.cjdns.total_nodes,
-            conn_types.connection_percentage(conn_types.cjdns.total_nodes)
-        )
+            share(stats.v2_transport),
+            share(stats.compact_filters),
+            share(stats.v2_and_filters),
+            connection_share(conn_types.ipv4.total_nodes),
+            connection_share(conn_types.ipv6.total_nodes),
+            connection_share(conn_types.tor_v2.total_nodes + conn_types.tor_v3.total_nodes),
+            connection_share(conn_types.i2p.total_nodes),
+            connection_share(conn_types.cjdns.total_nodes)
+        )?;
+
+        for (name, count) in &stats.combinations {
+            write!(f, " | {name}: {}", share(*count))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::node::{self, NodeFormat};
+    use crate::query::FeatureQuery;
+
+    /// Empty mainnet report taken at a time, with a number of listening nodes.
+    pub(crate) fn report(timestamp: u64, total_nodes: usize) -> CensusReport {
+        let mut report = CensusReport::from_stats(&NodeStats::new(), Network::Mainnet);
+        report.timestamp = timestamp;
+        report.stats.total_nodes = total_nodes;
+        report
+    }
+
+    #[test]
+    fn report_from_observations() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/observations.jsonl");
+        let observations = node::read_observations(&path, NodeFormat::Jsonl).unwrap();
+        assert_eq!(observations.len(), 5);
+
+        let query: FeatureQuery = "pruned_witness=pruned AND witness".parse().unwrap();
+        let mut stats = NodeStats::with_queries(vec![query]);
+        for observation in &observations {
+            stats.add_observation(observation);
+        }
+        let report = CensusReport::from_stats(&stats, Network::Mainnet);
+
+        assert_eq!(report.total_contacted, 5);
+        let stats = &report.stats;
+        assert_eq!(stats.total_nodes, 4);
+        assert_eq!(stats.v2_transport, 2);
+        assert_eq!(stats.compact_filters, 1);
+        assert_eq!(stats.v2_and_filters, 1);
+        assert_eq!(stats.service_flags["network"], 3);
+        assert_eq!(stats.service_flags["witness"], 2);
+        assert_eq!(stats.service_flags["network_limited"], 1);
+        assert_eq!(stats.combinations["pruned_witness"], 1);
+        assert_eq!(stats.protocol_versions["70016"], 2);
+        assert_eq!(stats.protocol_versions["unknown"], 1);
+        assert_eq!(stats.percentage(stats.v2_transport), 50.0);
+
+        let ipv4 = stats.connection_types.get(NetworkType::Ipv4);
+        assert_eq!(ipv4.total_nodes, 2);
+        assert_eq!(ipv4.v2_transport, 1);
+        assert_eq!(ipv4.combinations["pruned_witness"], 1);
+        assert_eq!(stats.connection_types.get(NetworkType::Ipv6).total_nodes, 1);
+        assert_eq!(
+            stats.connection_types.get(NetworkType::TorV3).total_nodes,
+            1
+        );
+        assert_eq!(
+            stats.confidence_intervals["v2_transport"],
+            stats.interval(stats.v2_transport)
+        );
+
+        let json = serde_json::to_string(&report).unwrap();
+        let parsed = parse_history(&json).unwrap();
+        assert_eq!(parsed[0].stats.total_nodes, 4);
+        assert_eq!(
+            parsed[0]
+                .stats
+                .connection_types
+                .get(NetworkType::Ipv4)
+                .total_nodes,
+            2
+        );
+    }
+
+    #[test]
+    fn csv_columns_keep_older_layout_as_prefix() {
+        let report = report(0, 0);
+        let with_intervals = report.format_csv(true, true).unwrap();
+        let without_intervals = report.format_csv(true, false).unwrap();
+        let header = with_intervals.lines().next().unwrap();
+        let old_header = without_intervals.lines().next().unwrap();
+        assert!(header.starts_with(&format!("{old_header},")));
+        assert!(header.ends_with("_pct_high"));
+    }
+
+    /// Path of a fresh temporary history file.
+    fn temp_history(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("census-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn append_jsonl() {
+        let path = temp_history("append.jsonl");
+        report(100, 1).append(OutputFormat::Jsonl, &path).unwrap();
+        report(200, 2).append(OutputFormat::Jsonl, &path).unwrap();
+
+        let history = read_history(&path).unwrap();
+        let timestamps: Vec<u64> = history.iter().map(|report| report.timestamp).collect();
+        assert_eq!(timestamps, vec![100, 200]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn append_after_missing_newline() {
+        let path = temp_history("newline.jsonl");
+        std::fs::write(&path, serde_json::to_string(&report(100, 1)).unwrap()).unwrap();
+        report(200, 2).append(OutputFormat::Jsonl, &path).unwrap();
+
+        let history = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(history.lines().count(), 2);
+        assert!(history.ends_with('\n'));
+        assert_eq!(parse_history(&history).unwrap().len(), 2);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn append_rejects_invalid_jsonl() {
+        let path = temp_history("invalid.jsonl");
+        let existing = format!(
+            "{}\nnot a report\n",
+            serde_json::to_string(&report(100, 1)).unwrap()
+        );
+        std::fs::write(&path, &existing).unwrap();
+
+        let error = report(200, 2)
+            .append(OutputFormat::Jsonl, &path)
+            .unwrap_err();
+        assert!(error.to_string().contains("line 2 is not a census report"));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), existing);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn append_csv() {
+        let path = temp_history("append.csv");
+        report(100, 1).append(OutputFormat::Csv, &path).unwrap();
+        report(200, 2).append(OutputFormat::Csv, &path).unwrap();
+
+        let history = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = history.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("timestamp,"));
+        assert!(lines[1..]
+            .iter()
+            .all(|line| !line.starts_with("timestamp,")));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn append_csv_without_intervals() {
+        let path = temp_history("old.csv");
+        let old = report(100, 1).format_csv(true, false).unwrap();
+        std::fs::write(&path, &old).unwrap();
+        report(200, 2).append(OutputFormat::Csv, &path).unwrap();
+
+        let history = std::fs::read_to_string(&path).unwrap();
+        let columns = |line: &str| line.split(',').count();
+        let lines: Vec<&str> = history.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(columns(lines[2]), columns(lines[0]));
+        assert!(!lines[0].contains("_pct_low"));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn append_rejects_other_csv_columns() {
+        let path = temp_history("other.csv");
+        std::fs::write(&path, "timestamp,nodes\n100,1\n").unwrap();
+
+        let error = report(200, 2).append(OutputFormat::Csv, &path).unwrap_err();
+        assert!(error.to_string().contains("different CSV columns"));
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "timestamp,nodes\n100,1\n"
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn append_only_histories() {
+        let path = temp_history("history.json");
+        assert!(report(100, 1).append(OutputFormat::Json, &path).is_err());
+        assert!(report(100, 1)
+            .append(OutputFormat::Prometheus, &path)
+            .is_err());
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn rewrite_keeps_backup() {
+        let path = temp_history("rewrite.jsonl");
+        let backup = sibling(&path, ".bak");
+        let _ = std::fs::remove_file(&backup);
+        report(100, 1).append(OutputFormat::Jsonl, &path).unwrap();
+        report(200, 2).append(OutputFormat::Jsonl, &path).unwrap();
+        let original = std::fs::read_to_string(&path).unwrap();
+
+        rewrite_history(&path, |mut reports| reports.split_off(1)).unwrap();
+        assert_eq!(std::fs::read_to_string(&backup).unwrap(), original);
+        let history = read_history(&path).unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].timestamp, 200);
+
+        // An earlier backup is never overwritten.
+        let error = rewrite_history(&path, |_| Vec::new()).unwrap_err();
+        assert!(error.to_string().contains("already exists"));
+        assert_eq!(std::fs::read_to_string(&backup).unwrap(), original);
+        assert_eq!(read_history(&path).unwrap().len(), 1);
+
+        report(300, 3).append(OutputFormat::Jsonl, &path).unwrap();
+        assert_eq!(read_history(&path).unwrap().len(), 2);
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(backup).unwrap();
     }
 }
@@ -0,0 +1,86 @@
+use log::warn;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+
+/// Read seeds from a file, one `host[:port]` per line. Blank lines and lines starting with `#` are skipped.
+pub fn read_seeds(path: &Path) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    Ok(std::fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect())
+}
+
+/// Append the default port to a seed if it does not specify one.
+///
+/// Seeds are hostnames, IPv4 or IPv6 addresses, optionally with a port (IPv6 in brackets).
+fn with_port(seed: &str, default_port: u16) -> String {
+    if seed.parse::<SocketAddr>().is_ok() {
+        return seed.to_string();
+    }
+
+    match seed.rsplit_once(':') {
+        Some((host, port)) if !host.contains(':') && port.parse::<u16>().is_ok() => {
+            seed.to_string()
+        }
+        _ if seed.parse::<IpAddr>().is_ok_and(|ip| ip.is_ipv6()) => {
+            format!("[{seed}]:{default_port}")
+        }
+        _ => format!("{seed}:{default_port}"),
+    }
+}
+
+/// Resolve seeds to addresses to start crawls from.
+///
+/// Only the first resolved address of each seed is used unless `expand_dns` is set, in which
+/// case every A/AAAA record becomes a seed. Seeds which fail to resolve are skipped.
+pub async fn resolve_seeds(
+    seeds: &[String],
+    default_port: u16,
+    expand_dns: bool,
+) -> Result<Vec<SocketAddr>, Box<dyn std::error::Error>> {
+    let mut resolved: Vec<SocketAddr> = Vec::new();
+
+    for seed in seeds {
+        let addrs = match tokio::net::lookup_host(with_port(seed, default_port)).await {
+            Ok(addrs) => addrs,
+            Err(e) => {
+                warn!("Failed to resolve seed {seed}: {e}");
+                continue;
+            }
+        };
+
+        let take = if expand_dns { usize::MAX } else { 1 };
+        for addr in addrs.take(take) {
+            if !resolved.contains(&addr) {
+                resolved.push(addr);
+            }
+        }
+    }
+
+    if resolved.is_empty() {
+        return Err("Failed to resolve any seed address".into());
+    }
+
+    Ok(resolved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn with_default_port() {
+        assert_eq!(with_port("1.2.3.4", 8333), "1.2.3.4:8333");
+        assert_eq!(with_port("1.2.3.4:18333", 8333), "1.2.3.4:18333");
+        assert_eq!(with_port("::1", 8333), "[::1]:8333");
+        assert_eq!(with_port("[::1]:18333", 8333), "[::1]:18333");
+        assert_eq!(with_port("2001:db8::1", 8333), "[2001:db8::1]:8333");
+        assert_eq!(with_port("seed.example.com", 8333), "seed.example.com:8333");
+        assert_eq!(
+            with_port("seed.example.com:18333", 8333),
+            "seed.example.com:18333"
+        );
+    }
+}
@@ -0,0 +1,320 @@
+use crate::node::NetworkType;
+use crate::report::{self, CensusReport};
+use crate::stats::Scope;
+use axum::extract::{Path, Query, State};
+use axum::http::{header, StatusCode};
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::{Json, Router};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+
+const INDEX_HTML: &str = include_str!("../site/index.html");
+const CENSUS_JS: &str = include_str!("../site/census.js");
+const FAVICON_SVG: &str = include_str!("../site/favicon.svg");
+
+/// Shared state of the request handlers.
+struct ServerState {
+    /// History file of census reports, one JSON report per line.
+    history: PathBuf,
+    /// Partial report of the crawl in progress, if any.
+    progress: watch::Receiver<Option<CensusReport>>,
+}
+
+/// Reports to select, in an inclusive time range as Unix timestamps in seconds.
+#[derive(Debug, Deserialize)]
+struct ReportFilter {
+    from: Option<u64>,
+    to: Option<u64>,
+    /// Also select partial reports of crawls in progress.
+    #[serde(default)]
+    partial: bool,
+}
+
+impl ReportFilter {
+    fn matches(&self, report: &CensusReport) -> bool {
+        (self.partial || !report.partial)
+            && self.from.is_none_or(|from| report.timestamp >= from)
+            && self.to.is_none_or(|to| report.timestamp <= to)
+    }
+}
+
+/// Value of a metric in a report.
+#[derive(Debug, Serialize)]
+struct MetricValue {
+    count: usize,
+    percentage: f64,
+    /// Bounds of the 95% confidence interval of the percentage.
+    percentage_low: f64,
+    percentage_high: f64,
+}
+
+/// Value of a metric in a report, overall and per connection type.
+#[derive(Debug, Serialize)]
+struct SeriesPoint {
+    timestamp: u64,
+    #[serde(flatten)]
+    total: MetricValue,
+    connection_types: BTreeMap<String, MetricValue>,
+}
+
+impl MetricValue {
+    fn new(scope: Scope, count: usize) -> Self {
+        let interval = scope.interval(count);
+        Self {
+            count,
+            percentage: scope.percentage(count),
+            percentage_low: interval.low,
+            percentage_high: interval.high,
+        }
+    }
+}
+
+impl SeriesPoint {
+    /// The metric's value in a report, none if the report does not count it.
+    fn from_report(report: &CensusReport, metric: &str) -> Option<Self> {
+        let total = Scope::from(&report.stats);
+        let count = total.count(metric)?;
+        let connection_types = NetworkType::ALL
+            .into_iter()
+            .map(|network| {
+                let scope = Scope::new(&report.stats, Some(network));
+                let count = scope.count(metric).unwrap_or(0);
+                (network.to_string(), MetricValue::new(scope, count))
+            })
+            .collect();
+
+        Some(Self {
+            timestamp: report.timestamp,
+            total: MetricValue::new(total, count),
+            connection_types,
+        })
+    }
+}
+
+/// Serve the dashboard, census history and JSON API until shutdown.
+///
+/// The dashboard files are bundled into the binary. The history file is read on every request,
+/// so reports appended while serving show up without a restart.
+///
+/// * `/api/latest` is the most recent complete report.
+/// * `/api/reports?from=&to=&partial=` are the complete reports in a time range, and the partial
+///   ones too with `partial=true`.
+/// * `/api/series/{metric}?from=&to=&partial=` is a metric of the same reports, per connection
+///   type. The metric is a count field (e.g. `v2_transport`), a service flag or a feature
+///   combination, and not found if no report counts it.
+/// * `/metrics` are OpenMetrics gauges of the latest complete report, prefixed `census`, and of
+///   the crawl in progress, prefixed `census_crawl`.
+pub async fn serve(
+    listener: TcpListener,
+    history: PathBuf,
+    progress: watch::Receiver<Option<CensusReport>>,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> std::io::Result<()> {
+    let state = Arc::new(ServerState { history, progress });
+    let app = Router::new()
+        .route("/", get(index))
+        .route("/index.html", get(index))
+        .route("/census.js", get(census_js))
+        .route("/favicon.svg", get(favicon))
+        .route("/census.jsonl", get(census_jsonl))
+        .route("/api/latest", get(latest))
+        .route("/api/reports", get(reports))
+        .route("/api/series/{metric}", get(series))
+        .route("/metrics", get(metrics))
+        .with_state(state);
+
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown)
+        .await
+}
+
+async fn index() -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/html; charset=utf-8")],
+        INDEX_HTML,
+    )
+}
+
+async fn census_js() -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/javascript; charset=utf-8")],
+        CENSUS_JS,
+    )
+}
+
+async fn favicon() -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, "image/svg+xml")], FAVICON_SVG)
+}
+
+async fn census_jsonl(State(state): State<Arc<ServerState>>) -> impl IntoResponse {
+    let history = read_raw_history(&state).await?;
+    Ok::<_, StatusCode>(([(header::CONTENT_TYPE, "application/jsonl")], history))
+}
+
+async fn latest(State(state): State<Arc<ServerState>>) -> impl IntoResponse {
+    let latest = load_reports(&state)
+        .await?
+        .into_iter()
+        .rfind(|report| !report.partial)
+        .ok_or(StatusCode::NOT_FOUND)?;
+    Ok::<_, StatusCode>(Json(latest))
+}
+
+async fn reports(
+    State(state): State<Arc<ServerState>>,
+    Query(filter): Query<ReportFilter>,
+) -> impl IntoResponse {
+    let reports: Vec<CensusReport> = load_reports(&state)
+        .await?
+        .into_iter()
+        .filter(|report| filter.matches(report))
+        .collect();
+    Ok::<_, StatusCode>(Json(reports))
+}
+
+async fn series(
+    State(state): State<Arc<ServerState>>,
+    Path(metric): Path<String>,
+    Query(filter): Query<ReportFilter>,
+) -> impl IntoResponse {
+    let reports = load_reports(&state).await?;
+    // A metric no report counts is unknown, rather than a series without points.
+    if !reports
+        .iter()
+        .any(|report| report.stats.count(&metric).is_some())
+    {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let points: Vec<SeriesPoint> = reports
+        .iter()
+        .filter(|report| filter.matches(report))
+        .filter_map(|report| SeriesPoint::from_report(report, &metric))
+        .collect();
+    Ok(Json(points))
+}
+
+async fn metrics(State(state): State<Arc<ServerState>>) -> impl IntoResponse {
+    let latest = match load_reports(&state).await {
+        Ok(reports) => reports.into_iter().rfind(|report| !report.partial),
+        Err(StatusCode::NOT_FOUND) => None,
+        Err(status) => return Err(status),
+    };
+    let progress = state.progress.borrow().clone();
+    let body = format_metrics(latest.as_ref(), progress.as_ref())
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok((
+        [(
+            header::CONTENT_TYPE,
+            "application/openmetrics-text; version=1.0.0; charset=utf-8",
+        )],
+        body,
+    ))
+}
+
+/// Format the metrics of the latest report and the crawl in progress.
+fn format_metrics(
+    latest: Option<&CensusReport>,
+    progress: Option<&CensusReport>,
+) -> Result<String, std::fmt::Error> {
+    let mut out = String::new();
+    if let Some(report) = latest {
+        report.write_openmetrics("census", &mut out)?;
+    }
+    writeln!(out, "# TYPE census_crawl_in_progress gauge")?;
+    writeln!(
+        out,
+        "# HELP census_crawl_in_progress Whether a crawl is running."
+    )?;
+    writeln!(
+        out,
+        "census_crawl_in_progress {}",
+        u8::from(progress.is_some())
+    )?;
+    if let Some(report) = progress {
+        report.write_openmetrics("census_crawl", &mut out)?;
+    }
+    writeln!(out, "# EOF")?;
+
+    Ok(out)
+}
+
+/// Read the raw history file, a missing file is not found.
+async fn read_raw_history(state: &ServerState) -> Result<String, StatusCode> {
+    match tokio::fs::read_to_string(&state.history).await {
+        Ok(history) => Ok(history),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(StatusCode::NOT_FOUND),
+        Err(e) => {
+            log::warn!("Failed to read history {}: {e}", state.history.display());
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// Load the reports of the history file, sorted by timestamp.
+async fn load_reports(state: &ServerState) -> Result<Vec<CensusReport>, StatusCode> {
+    let mut reports = report::parse_history(&read_raw_history(state).await?).map_err(|e| {
+        log::warn!("Failed to parse history {}: {e}", state.history.display());
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    reports.sort_by_key(|report| report.timestamp);
+    Ok(reports)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::tests::report;
+
+    fn filter(from: Option<u64>, to: Option<u64>, partial: bool) -> ReportFilter {
+        ReportFilter { from, to, partial }
+    }
+
+    #[test]
+    fn filter_reports() {
+        let complete = report(100, 10);
+        let mut partial = report(100, 5);
+        partial.partial = true;
+
+        assert!(filter(None, None, false).matches(&complete));
+        assert!(!filter(None, None, false).matches(&partial));
+        assert!(filter(None, None, true).matches(&partial));
+        assert!(filter(Some(100), Some(100), false).matches(&complete));
+        assert!(!filter(Some(101), None, false).matches(&complete));
+        assert!(!filter(None, Some(99), false).matches(&complete));
+    }
+
+    #[test]
+    fn series_points() {
+        let mut report = report(100, 10);
+        report.stats.v2_transport = 5;
+        let ipv4 = report.stats.connection_types.get_mut(NetworkType::Ipv4);
+        ipv4.total_nodes = 4;
+        ipv4.v2_transport = 1;
+
+        let point = SeriesPoint::from_report(&report, "v2_transport").unwrap();
+        assert_eq!(point.timestamp, 100);
+        assert_eq!((point.total.count, point.total.percentage), (5, 50.0));
+        assert!(point.total.percentage_low < 50.0 && point.total.percentage_high > 50.0);
+        let ipv4 = &point.connection_types["ipv4"];
+        assert_eq!((ipv4.count, ipv4.percentage), (1, 25.0));
+        assert_eq!(point.connection_types["tor_v3"].count, 0);
+        assert_eq!(point.connection_types.len(), NetworkType::ALL.len());
+
+        assert_eq!(
+            SeriesPoint::from_report(&report, "bloom")
+                .unwrap()
+                .total
+                .count,
+            0
+        );
+        assert!(SeriesPoint::from_report(&report, "nonsense").is_none());
+    }
+}
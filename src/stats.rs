@@ -1,7 +1,227 @@
-use bitcoin::p2p::address::AddrV2;
+use crate::node::{NetworkType, NodeObservation};
+use crate::query::FeatureQuery;
 use bitcoin::p2p::ServiceFlags;
-use bitcoin_peers_crawler::{Peer, PeerServices};
+use bitcoin_peers_crawler::{Peer, PeerProtocolVersion, PeerServices};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Named service flags, all other bits are reported by position.
+const KNOWN_SERVICE_FLAGS: [(ServiceFlags, &str); 7] = [
+    (ServiceFlags::NETWORK, "network"),
+    (ServiceFlags::GETUTXO, "getutxo"),
+    (ServiceFlags::BLOOM, "bloom"),
+    (ServiceFlags::WITNESS, "witness"),
+    (ServiceFlags::COMPACT_FILTERS, "compact_filters"),
+    (ServiceFlags::NETWORK_LIMITED, "network_limited"),
+    (ServiceFlags::P2P_V2, "p2p_v2"),
+];
+
+/// Standard normal quantile of the 95% confidence intervals of percentages.
+const CONFIDENCE_Z: f64 = 1.96;
+
+/// Shorthand names accepted when parsing service flags.
+const SERVICE_FLAG_ALIASES: [(&str, &str); 3] = [
+    ("pruned", "network_limited"),
+    ("v2", "p2p_v2"),
+    ("filters", "compact_filters"),
+];
+
+/// Parse a service flag from its name, an alias, or `bit_<position>`.
+pub fn parse_service_flag(name: &str) -> Option<ServiceFlags> {
+    let name = name.to_lowercase();
+    let name = SERVICE_FLAG_ALIASES
+        .iter()
+        .find(|(alias, _)| *alias == name)
+        .map_or(name.as_str(), |(_, flag)| *flag);
+
+    if let Some(bit) = name.strip_prefix("bit_") {
+        return bit
+            .parse::<u32>()
+            .ok()
+            .filter(|bit| *bit < u64::BITS)
+            .map(|bit| ServiceFlags::from(1u64 << bit));
+    }
+
+    KNOWN_SERVICE_FLAGS
+        .iter()
+        .find(|(_, known)| *known == name)
+        .map(|(flag, _)| *flag)
+}
+
+/// Names of every service flag set, known flags by name and unknown flags as `bit_<position>`.
+pub fn service_flag_names(flags: ServiceFlags) -> Vec<String> {
+    (0..u64::BITS)
+        .map(|bit| ServiceFlags::from(1u64 << bit))
+        .filter(|flag| flags.has(*flag))
+        .map(|flag| {
+            KNOWN_SERVICE_FLAGS
+                .iter()
+                .find(|(known, _)| *known == flag)
+                .map(|(_, name)| name.to_string())
+                .unwrap_or_else(|| format!("bit_{}", flag.to_u64().trailing_zeros()))
+        })
+        .collect()
+}
+
+/// 95% confidence interval of a percentage.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct ConfidenceInterval {
+    /// Lower bound in percent.
+    pub low: f64,
+    /// Upper bound in percent.
+    pub high: f64,
+}
+
+/// Percentage of a count, zero if the total is.
+pub fn percentage(count: usize, total: usize) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        (count as f64 / total as f64) * 100.0
+    }
+}
+
+/// Median of values, the lower one for an even number of values and the default for none.
+pub fn median<T: Ord + Copy + Default>(mut values: Vec<T>) -> T {
+    values.sort_unstable();
+    values
+        .get(values.len().saturating_sub(1) / 2)
+        .copied()
+        .unwrap_or_default()
+}
+
+/// 95% Wilson score interval of a percentage.
+///
+/// Unlike the normal approximation, the interval stays within 0-100% and is honest about small
+/// samples, e.g. 1 of 2 nodes is 9.5-90.5%. An empty sample is 0-100%.
+pub fn wilson_interval(count: usize, total: usize) -> ConfidenceInterval {
+    if total == 0 {
+        return ConfidenceInterval {
+            low: 0.0,
+            high: 100.0,
+        };
+    }
+
+    let n = total as f64;
+    let p = count as f64 / n;
+    let z2 = CONFIDENCE_Z * CONFIDENCE_Z;
+    let center = (p + z2 / (2.0 * n)) / (1.0 + z2 / n);
+    let margin = CONFIDENCE_Z / (1.0 + z2 / n) * (p * (1.0 - p) / n + z2 / (4.0 * n * n)).sqrt();
+    ConfidenceInterval {
+        low: ((center - margin) * 100.0).max(0.0),
+        high: ((center + margin) * 100.0).min(100.0),
+    }
+}
+
+/// Counts of the whole network or a single connection type, with percentages of its nodes.
+#[derive(Debug, Clone, Copy)]
+pub struct Scope<'a> {
+    pub total_nodes: usize,
+    pub v2_transport: usize,
+    pub compact_filters: usize,
+    pub v2_and_filters: usize,
+    pub service_flags: &'a BTreeMap<String, usize>,
+    pub combinations: &'a BTreeMap<String, usize>,
+    pub protocol_versions: &'a BTreeMap<String, usize>,
+}
+
+impl<'a> Scope<'a> {
+    /// Counts of a connection type, or the whole network if there is none.
+    pub fn new(stats: &'a FeatureStats, network: Option<NetworkType>) -> Self {
+        match network {
+            Some(network) => stats.connection_types.get(network).into(),
+            None => stats.into(),
+        }
+    }
+
+    /// Every counted field by name.
+    pub fn fields(&self) -> [(&'static str, usize); 4] {
+        [
+            ("total_nodes", self.total_nodes),
+            ("v2_transport", self.v2_transport),
+            ("compact_filters", self.compact_filters),
+            ("v2_and_filters", self.v2_and_filters),
+        ]
+    }
+
+    /// Every map of counts by name.
+    pub fn maps(&self) -> [(&'static str, &'a BTreeMap<String, usize>); 3] {
+        [
+            ("service_flags", self.service_flags),
+            ("combinations", self.combinations),
+            ("protocol_versions", self.protocol_versions),
+        ]
+    }
+
+    /// Count of nodes for a metric, which is a field name, feature combination or service flag.
+    ///
+    /// Service flags may be given by alias and count zero when no node advertised them.
+    pub fn count(&self, metric: &str) -> Option<usize> {
+        if let Some((_, count)) = self.fields().into_iter().find(|(name, _)| *name == metric) {
+            return Some(count);
+        }
+        if let Some(count) = self.combinations.get(metric) {
+            return Some(*count);
+        }
+
+        let name = service_flag_names(parse_service_flag(metric)?).pop()?;
+        Some(self.service_flags.get(&name).copied().unwrap_or(0))
+    }
+
+    /// Percentage of the scope's nodes.
+    pub fn percentage(&self, count: usize) -> f64 {
+        percentage(count, self.total_nodes)
+    }
+
+    /// 95% confidence interval of [`Self::percentage`].
+    pub fn interval(&self, count: usize) -> ConfidenceInterval {
+        wilson_interval(count, self.total_nodes)
+    }
+
+    /// Confidence intervals of the percentages of the features and feature combinations.
+    fn confidence_intervals(&self) -> BTreeMap<String, ConfidenceInterval> {
+        let [_, features @ ..] = self.fields();
+        features
+            .into_iter()
+            .map(|(name, count)| (name.to_string(), count))
+            .chain(
+                self.combinations
+                    .iter()
+                    .map(|(name, count)| (name.clone(), *count)),
+            )
+            .map(|(name, count)| (name, self.interval(count)))
+            .collect()
+    }
+}
+
+impl<'a> From<&'a FeatureStats> for Scope<'a> {
+    fn from(stats: &'a FeatureStats) -> Self {
+        Self {
+            total_nodes: stats.total_nodes,
+            v2_transport: stats.v2_transport,
+            compact_filters: stats.compact_filters,
+            v2_and_filters: stats.v2_and_filters,
+            service_flags: &stats.service_flags,
+            combinations: &stats.combinations,
+            protocol_versions: &stats.protocol_versions,
+        }
+    }
+}
+
+impl<'a> From<&'a ConnectionTypeFeatures> for Scope<'a> {
+    fn from(features: &'a ConnectionTypeFeatures) -> Self {
+        Self {
+            total_nodes: features.total_nodes,
+            v2_transport: features.v2_transport,
+            compact_filters: features.compact_filters,
+            v2_and_filters: features.v2_and_filters,
+            service_flags: &features.service_flags,
+            combinations: &features.combinations,
+            protocol_versions: &features.protocol_versions,
+        }
+    }
+}
 
 /// Feature statistics for a specific connection type.
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -15,6 +235,19 @@ pub struct ConnectionTypeFeatures {
     pub compact_filters: usize,
     /// Nodes supporting both v2 transport AND compact filters.
     pub v2_and_filters: usize,
+    /// Nodes advertising each service flag, unknown flags are keyed by bit position.
+    #[serde(default)]
+    pub service_flags: BTreeMap<String, usize>,
+    /// Nodes matching each configured feature query.
+    #[serde(default)]
+    pub combinations: BTreeMap<String, usize>,
+    /// Nodes advertising each protocol version.
+    #[serde(default)]
+    pub protocol_versions: BTreeMap<String, usize>,
+    /// 95% confidence intervals of the percentages of the features and feature combinations of
+    /// this connection type's nodes, and of `total_nodes` among all nodes.
+    #[serde(default)]
+    pub confidence_intervals: BTreeMap<String, ConfidenceInterval>,
 }
 
 impl Default for ConnectionTypeFeatures {
@@ -30,11 +263,19 @@ impl ConnectionTypeFeatures {
             v2_transport: 0,
             compact_filters: 0,
             v2_and_filters: 0,
+            service_flags: BTreeMap::new(),
+            combinations: BTreeMap::new(),
+            protocol_versions: BTreeMap::new(),
+            confidence_intervals: BTreeMap::new(),
         }
     }
 
-    /// Add a node with the given features to this connection type.
-    pub fn add_node(&mut self, has_v2: bool, has_filters: bool) {
+    /// Add a node with the given service flags to this connection type.
+    pub fn add_node(&mut self, services: Option<ServiceFlags>) {
+        let flags = services.unwrap_or(ServiceFlags::NONE);
+        let has_v2 = flags.has(ServiceFlags::P2P_V2);
+        let has_filters = flags.has(ServiceFlags::COMPACT_FILTERS);
+
         self.total_nodes += 1;
 
         if has_v2 {
@@ -48,15 +289,25 @@ impl ConnectionTypeFeatures {
         if has_v2 && has_filters {
             self.v2_and_filters += 1;
         }
+
+        for name in service_flag_names(flags) {
+            *self.service_flags.entry(name).or_insert(0) += 1;
+        }
+    }
+
+    /// Count of nodes for a metric, see [`Scope::count`].
+    pub fn count(&self, metric: &str) -> Option<usize> {
+        Scope::from(self).count(metric)
     }
 
     /// Calculate percentage of nodes with a feature for this connection type.
     pub fn percentage(&self, count: usize) -> f64 {
-        if self.total_nodes == 0 {
-            0.0
-        } else {
-            (count as f64 / self.total_nodes as f64) * 100.0
-        }
+        Scope::from(self).percentage(count)
+    }
+
+    /// 95% confidence interval of [`Self::percentage`].
+    pub fn interval(&self, count: usize) -> ConfidenceInterval {
+        Scope::from(self).interval(count)
     }
 }
 
@@ -111,26 +362,44 @@ impl ConnectionTypeStats {
     }
 
     /// Add a node with features to the appropriate connection type.
-    pub fn add_node(&mut self, addr: &AddrV2, has_v2: bool, has_filters: bool) {
-        match addr {
-            AddrV2::Ipv4(_) => self.ipv4.add_node(has_v2, has_filters),
-            AddrV2::Ipv6(_) => self.ipv6.add_node(has_v2, has_filters),
-            AddrV2::TorV2(_) => self.tor_v2.add_node(has_v2, has_filters),
-            AddrV2::TorV3(_) => self.tor_v3.add_node(has_v2, has_filters),
-            AddrV2::I2p(_) => self.i2p.add_node(has_v2, has_filters),
-            AddrV2::Cjdns(_) => self.cjdns.add_node(has_v2, has_filters),
-            AddrV2::Unknown(_, _) => self.unknown.add_node(has_v2, has_filters),
+    pub fn add_node(&mut self, network: NetworkType, services: Option<ServiceFlags>) {
+        self.get_mut(network).add_node(services);
+    }
+
+    /// Get the stats of a connection type.
+    pub fn get(&self, network: NetworkType) -> &ConnectionTypeFeatures {
+        match network {
+            NetworkType::Ipv4 => &self.ipv4,
+            NetworkType::Ipv6 => &self.ipv6,
+            NetworkType::TorV2 => &self.tor_v2,
+            NetworkType::TorV3 => &self.tor_v3,
+            NetworkType::I2p => &self.i2p,
+            NetworkType::Cjdns => &self.cjdns,
+            NetworkType::Unknown => &self.unknown,
+        }
+    }
+
+    /// Get the mutable stats of a connection type.
+    pub fn get_mut(&mut self, network: NetworkType) -> &mut ConnectionTypeFeatures {
+        match network {
+            NetworkType::Ipv4 => &mut self.ipv4,
+            NetworkType::Ipv6 => &mut self.ipv6,
+            NetworkType::TorV2 => &mut self.tor_v2,
+            NetworkType::TorV3 => &mut self.tor_v3,
+            NetworkType::I2p => &mut self.i2p,
+            NetworkType::Cjdns => &mut self.cjdns,
+            NetworkType::Unknown => &mut self.unknown,
         }
     }
 
     /// Calculate percentage for a connection type based on total nodes.
     pub fn connection_percentage(&self, conn_type_total: usize) -> f64 {
-        let total = self.total_nodes();
-        if total == 0 {
-            0.0
-        } else {
-            (conn_type_total as f64 / total as f64) * 100.0
-        }
+        percentage(conn_type_total, self.total_nodes())
+    }
+
+    /// 95% confidence interval of [`Self::connection_percentage`].
+    pub fn connection_interval(&self, conn_type_total: usize) -> ConfidenceInterval {
+        wilson_interval(conn_type_total, self.total_nodes())
     }
 }
 
@@ -146,6 +415,18 @@ pub struct FeatureStats {
     pub compact_filters: usize,
     /// Nodes supporting both v2 transport AND compact filters (sum across all connection types).
     pub v2_and_filters: usize,
+    /// Nodes advertising each service flag (sum across all connection types).
+    #[serde(default)]
+    pub service_flags: BTreeMap<String, usize>,
+    /// Nodes matching each configured feature query (sum across all connection types).
+    #[serde(default)]
+    pub combinations: BTreeMap<String, usize>,
+    /// Nodes advertising each protocol version (sum across all connection types).
+    #[serde(default)]
+    pub protocol_versions: BTreeMap<String, usize>,
+    /// 95% confidence intervals of the percentages of the features and feature combinations.
+    #[serde(default)]
+    pub confidence_intervals: BTreeMap<String, ConfidenceInterval>,
     /// Detailed breakdown by connection type.
     pub connection_types: ConnectionTypeStats,
 }
@@ -163,16 +444,41 @@ impl FeatureStats {
             v2_transport: 0,
             compact_filters: 0,
             v2_and_filters: 0,
+            service_flags: BTreeMap::new(),
+            combinations: BTreeMap::new(),
+            protocol_versions: BTreeMap::new(),
+            confidence_intervals: BTreeMap::new(),
             connection_types: ConnectionTypeStats::new(),
         }
     }
 
+    /// Count of nodes for a metric, see [`Scope::count`].
+    pub fn count(&self, metric: &str) -> Option<usize> {
+        Scope::from(self).count(metric)
+    }
+
     /// Calculate percentage of nodes with a feature.
     pub fn percentage(&self, count: usize) -> f64 {
-        if self.total_nodes == 0 {
-            0.0
-        } else {
-            (count as f64 / self.total_nodes as f64) * 100.0
+        Scope::from(self).percentage(count)
+    }
+
+    /// 95% confidence interval of [`Self::percentage`].
+    pub fn interval(&self, count: usize) -> ConfidenceInterval {
+        Scope::from(self).interval(count)
+    }
+
+    /// Recompute the confidence intervals, overall and per connection type, from the counts.
+    pub fn update_confidence_intervals(&mut self) {
+        self.confidence_intervals = Scope::from(&*self).confidence_intervals();
+        for network in NetworkType::ALL {
+            let share = self
+                .connection_types
+                .connection_interval(self.connection_types.get(network).total_nodes);
+            let features = self.connection_types.get_mut(network);
+            features.confidence_intervals = Scope::from(&*features).confidence_intervals();
+            features
+                .confidence_intervals
+                .insert("total_nodes".to_string(), share);
         }
     }
 }
@@ -180,6 +486,8 @@ impl FeatureStats {
 /// Container for all node statistics.
 pub struct NodeStats {
     pub features: FeatureStats,
+    /// Feature combinations counted for each node.
+    queries: Vec<FeatureQuery>,
     duration_seconds: u64,
     /// Total number of nodes contacted (listening + non-listening).
     total_contacted: usize,
@@ -195,26 +503,72 @@ impl NodeStats {
     pub fn new() -> Self {
         Self {
             features: FeatureStats::new(),
+            queries: Vec::new(),
             duration_seconds: 0,
             total_contacted: 0,
         }
     }
 
+    /// Create stats which also count nodes matching each query.
+    pub fn with_queries(queries: Vec<FeatureQuery>) -> Self {
+        let mut stats = Self::new();
+
+        // Report every query, even if no nodes match.
+        for query in &queries {
+            stats.features.combinations.insert(query.name.clone(), 0);
+            for network in NetworkType::ALL {
+                stats
+                    .features
+                    .connection_types
+                    .get_mut(network)
+                    .combinations
+                    .insert(query.name.clone(), 0);
+            }
+        }
+        stats.queries = queries;
+
+        stats
+    }
+
     pub fn add_node(&mut self, peer: Peer) {
-        // Determine features from service flags
-        let (has_v2, has_filters) = if let PeerServices::Known(flags) = peer.services {
-            (
-                flags.has(ServiceFlags::P2P_V2),
-                flags.has(ServiceFlags::COMPACT_FILTERS),
-            )
-        } else {
-            (false, false)
+        let services = match peer.services {
+            PeerServices::Known(flags) => Some(flags),
+            PeerServices::Unknown => None,
         };
+        let version = match peer.version {
+            PeerProtocolVersion::Known(version) => Some(version),
+            PeerProtocolVersion::Unknown => None,
+        };
+
+        self.record(NetworkType::from(&peer.address), services, version);
+    }
+
+    /// Add a node observation, listening nodes are also added to the feature stats.
+    pub fn add_observation(&mut self, observation: &NodeObservation) {
+        self.increment_contacted();
 
+        if observation.listening {
+            self.record(
+                observation.network,
+                observation.services.map(ServiceFlags::from),
+                observation.version,
+            );
+        }
+    }
+
+    fn record(
+        &mut self,
+        network: NetworkType,
+        services: Option<ServiceFlags>,
+        version: Option<u32>,
+    ) {
         // Add to connection type-specific stats
-        self.features
-            .connection_types
-            .add_node(&peer.address, has_v2, has_filters);
+        self.features.connection_types.add_node(network, services);
+
+        // Determine features from service flags
+        let flags = services.unwrap_or(ServiceFlags::NONE);
+        let has_v2 = flags.has(ServiceFlags::P2P_V2);
+        let has_filters = flags.has(ServiceFlags::COMPACT_FILTERS);
 
         // Update aggregate stats
         self.features.total_nodes += 1;
@@ -227,6 +581,38 @@ impl NodeStats {
         if has_v2 && has_filters {
             self.features.v2_and_filters += 1;
         }
+        for name in service_flag_names(flags) {
+            *self.features.service_flags.entry(name).or_insert(0) += 1;
+        }
+
+        for query in self.queries.iter().filter(|query| query.matches(flags)) {
+            *self
+                .features
+                .combinations
+                .entry(query.name.clone())
+                .or_insert(0) += 1;
+            *self
+                .features
+                .connection_types
+                .get_mut(network)
+                .combinations
+                .entry(query.name.clone())
+                .or_insert(0) += 1;
+        }
+
+        let version = version.map_or_else(|| "unknown".to_string(), |v| v.to_string());
+        *self
+            .features
+            .protocol_versions
+            .entry(version.clone())
+            .or_insert(0) += 1;
+        *self
+            .features
+            .connection_types
+            .get_mut(network)
+            .protocol_versions
+            .entry(version)
+            .or_insert(0) += 1;
     }
 
     /// Increment the total number of nodes contacted.
@@ -250,3 +636,91 @@ impl NodeStats {
         self.duration_seconds
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn service_flags_by_name() {
+        assert_eq!(
+            parse_service_flag("pruned"),
+            Some(ServiceFlags::NETWORK_LIMITED)
+        );
+        assert_eq!(parse_service_flag("P2P_V2"), Some(ServiceFlags::P2P_V2));
+        assert_eq!(
+            parse_service_flag("bit_30"),
+            Some(ServiceFlags::from(1 << 30))
+        );
+        assert_eq!(parse_service_flag("bit_64"), None);
+        assert_eq!(parse_service_flag("nonsense"), None);
+        assert_eq!(
+            service_flag_names(ServiceFlags::WITNESS | ServiceFlags::from(1 << 30)),
+            vec!["witness", "bit_30"]
+        );
+    }
+
+    #[test]
+    fn scope_counts() {
+        let mut stats = FeatureStats::new();
+        stats.total_nodes = 4;
+        stats.v2_transport = 1;
+        stats.service_flags.insert("network_limited".to_string(), 2);
+        stats.combinations.insert("pruned_v2".to_string(), 1);
+        stats
+            .connection_types
+            .get_mut(NetworkType::Ipv4)
+            .total_nodes = 2;
+        stats
+            .connection_types
+            .get_mut(NetworkType::Ipv4)
+            .v2_transport = 1;
+
+        let all = Scope::new(&stats, None);
+        assert_eq!(all.count("v2_transport"), Some(1));
+        assert_eq!(all.count("pruned"), Some(2));
+        assert_eq!(all.count("bloom"), Some(0));
+        assert_eq!(all.count("pruned_v2"), Some(1));
+        assert_eq!(all.count("nonsense"), None);
+        assert_eq!(all.percentage(1), 25.0);
+
+        let ipv4 = Scope::new(&stats, Some(NetworkType::Ipv4));
+        assert_eq!(ipv4.percentage(ipv4.count("v2_transport").unwrap()), 50.0);
+        assert_eq!(
+            Scope::new(&stats, Some(NetworkType::Ipv6)).percentage(0),
+            0.0
+        );
+    }
+
+    /// Whether two percentages agree to a hundredth of a point.
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 0.01
+    }
+
+    #[test]
+    fn wilson_intervals() {
+        let half = wilson_interval(1, 2);
+        assert!(close(half.low, 9.45) && close(half.high, 90.55));
+
+        let large = wilson_interval(500, 1000);
+        assert!(close(large.low, 46.91) && close(large.high, 53.09));
+
+        let none = wilson_interval(0, 10);
+        assert_eq!(none.low, 0.0);
+        assert!(close(none.high, 27.75));
+
+        let all = wilson_interval(10, 10);
+        assert!(close(all.low, 72.25));
+        assert_eq!(all.high, 100.0);
+
+        let empty = wilson_interval(0, 0);
+        assert_eq!((empty.low, empty.high), (0.0, 100.0));
+    }
+
+    #[test]
+    fn medians() {
+        assert_eq!(median(vec![3, 1, 2]), 2);
+        assert_eq!(median(vec![4, 1, 3, 2]), 2);
+        assert_eq!(median(Vec::<u64>::new()), 0);
+    }
+}
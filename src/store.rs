@@ -0,0 +1,237 @@
+use crate::node::{NetworkType, NodeObservation};
+use crate::report::CensusReport;
+use rusqlite::{params, Connection};
+use std::error::Error;
+use std::path::Path;
+
+/// Version of the database schema, stored in the `user_version` pragma.
+const SCHEMA_VERSION: i64 = 1;
+
+/// Schema of the database.
+///
+/// Reports are stored whole as JSON along with their headline counts, so the history can be
+/// queried with plain SQL. Node observations reference the report of their crawl.
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS reports (
+    id INTEGER PRIMARY KEY,
+    timestamp INTEGER NOT NULL,
+    network TEXT NOT NULL,
+    duration_seconds INTEGER NOT NULL,
+    total_contacted INTEGER NOT NULL,
+    total_nodes INTEGER NOT NULL,
+    v2_transport INTEGER NOT NULL,
+    compact_filters INTEGER NOT NULL,
+    v2_and_filters INTEGER NOT NULL,
+    partial INTEGER NOT NULL,
+    report TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS reports_timestamp ON reports (timestamp);
+CREATE TABLE IF NOT EXISTS nodes (
+    report_id INTEGER NOT NULL REFERENCES reports (id) ON DELETE CASCADE,
+    address TEXT NOT NULL,
+    port INTEGER NOT NULL,
+    network TEXT NOT NULL,
+    services INTEGER,
+    version INTEGER,
+    listening INTEGER NOT NULL,
+    timestamp INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS nodes_report ON nodes (report_id);
+";
+
+/// SQLite database of census reports and node observations.
+pub struct Store {
+    conn: Connection,
+}
+
+impl Store {
+    /// Open a database, creating it and its schema if needed.
+    pub fn open(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let conn = Connection::open(path)?;
+        conn.pragma_update(None, "foreign_keys", true)?;
+
+        let version: i64 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        if version > SCHEMA_VERSION {
+            return Err(format!(
+                "{} has schema version {version}, newer than supported version {SCHEMA_VERSION}",
+                path.display()
+            )
+            .into());
+        }
+        conn.execute_batch(SCHEMA)?;
+        conn.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+
+        Ok(Self { conn })
+    }
+
+    /// Insert a report and the node observations of its crawl, returning the report's id.
+    pub fn insert_report(
+        &mut self,
+        report: &CensusReport,
+        nodes: &[NodeObservation],
+    ) -> Result<i64, Box<dyn Error>> {
+        let tx = self.conn.transaction()?;
+        let stats = &report.stats;
+        tx.execute(
+            "INSERT INTO reports (timestamp, network, duration_seconds, total_contacted,
+                total_nodes, v2_transport, compact_filters, v2_and_filters, partial, report)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            params![
+                report.timestamp,
+                report.network.to_string(),
+                report.duration_seconds,
+                report.total_contacted,
+                stats.total_nodes,
+                stats.v2_transport,
+                stats.compact_filters,
+                stats.v2_and_filters,
+                report.partial,
+                serde_json::to_string(report)?,
+            ],
+        )?;
+        let report_id = tx.last_insert_rowid();
+
+        {
+            let mut insert = tx.prepare(
+                "INSERT INTO nodes (report_id, address, port, network, services, version,
+                    listening, timestamp)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            )?;
+            for node in nodes {
+                insert.execute(params![
+                    report_id,
+                    node.address,
+                    node.port,
+                    node.network.to_string(),
+                    // SQLite integers are signed, so the flag bits are stored as is.
+                    node.services.map(|services| services as i64),
+                    node.version,
+                    node.listening,
+                    node.timestamp,
+                ])?;
+            }
+        }
+        tx.commit()?;
+
+        Ok(report_id)
+    }
+
+    /// Every stored report with its id, oldest first.
+    pub fn reports(&self) -> Result<Vec<(i64, CensusReport)>, Box<dyn Error>> {
+        let mut select = self
+            .conn
+            .prepare("SELECT id, report FROM reports ORDER BY timestamp, id")?;
+        let rows = select.query_map([], |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+        })?;
+
+        let mut reports = Vec::new();
+        for row in rows {
+            let (id, report) = row?;
+            reports.push((id, serde_json::from_str(&report)?));
+        }
+        Ok(reports)
+    }
+
+    /// Node observations stored with a report.
+    pub fn nodes(&self, report_id: i64) -> Result<Vec<NodeObservation>, Box<dyn Error>> {
+        let mut select = self.conn.prepare(
+            "SELECT address, port, network, services, version, listening, timestamp
+             FROM nodes WHERE report_id = ?1",
+        )?;
+        let rows = select.query_map([report_id], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, u16>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, Option<i64>>(3)?,
+                row.get::<_, Option<u32>>(4)?,
+                row.get::<_, bool>(5)?,
+                row.get::<_, u64>(6)?,
+            ))
+        })?;
+
+        let mut nodes = Vec::new();
+        for row in rows {
+            let (address, port, network, services, version, listening, timestamp) = row?;
+            let network = NetworkType::ALL
+                .into_iter()
+                .find(|known| known.to_string() == network)
+                .ok_or_else(|| format!("unknown network type: {network}"))?;
+            nodes.push(NodeObservation {
+                address,
+                port,
+                network,
+                services: services.map(|services| services as u64),
+                version,
+                listening,
+                timestamp,
+            });
+        }
+        Ok(nodes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::tests::report;
+
+    #[test]
+    fn round_trip() {
+        let mut store = Store::open(Path::new(":memory:")).unwrap();
+        let nodes = [
+            NodeObservation {
+                address: "203.0.113.1".to_string(),
+                port: 8333,
+                network: NetworkType::Ipv4,
+                // Above i64::MAX, so stored as a negative integer.
+                services: Some(1 << 63 | 0x409),
+                version: Some(70016),
+                listening: true,
+                timestamp: 100,
+            },
+            NodeObservation {
+                address: "2001:db8::1".to_string(),
+                port: 18333,
+                network: NetworkType::Ipv6,
+                services: None,
+                version: None,
+                listening: false,
+                timestamp: 101,
+            },
+        ];
+        let later = store.insert_report(&report(200, 2), &[]).unwrap();
+        let earlier = store.insert_report(&report(100, 1), &nodes).unwrap();
+
+        let reports = store.reports().unwrap();
+        let stored: Vec<(i64, u64)> = reports
+            .iter()
+            .map(|(id, report)| (*id, report.timestamp))
+            .collect();
+        assert_eq!(stored, vec![(earlier, 100), (later, 200)]);
+        assert_eq!(reports[0].1.stats.total_nodes, 1);
+
+        let stored = store.nodes(earlier).unwrap();
+        assert_eq!(
+            serde_json::to_value(&stored).unwrap(),
+            serde_json::to_value(&nodes).unwrap()
+        );
+        assert!(store.nodes(later).unwrap().is_empty());
+    }
+
+    #[test]
+    fn newer_schema() {
+        let path = std::env::temp_dir().join(format!("census-{}-newer.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        Store::open(&path).unwrap();
+        Connection::open(&path)
+            .unwrap()
+            .pragma_update(None, "user_version", SCHEMA_VERSION + 1)
+            .unwrap();
+
+        let error = Store::open(&path).err().unwrap();
+        assert!(error.to_string().contains("newer than supported"));
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,293 @@
+use crate::node::NetworkType;
+use crate::report::{CensusReport, StopReason};
+use crate::stats::Scope;
+use serde::Serialize;
+use std::error::Error;
+use std::fmt;
+
+/// Kind of a metric checked against its trend.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MetricKind {
+    /// Listening nodes, compared by relative change.
+    Nodes,
+    /// Percentage of listening nodes, compared by percentage points.
+    Share,
+}
+
+/// A metric of the latest report deviating from the trend of the reports before it.
+#[derive(Debug, Clone, Serialize)]
+pub struct Deviation {
+    /// Connection type of the metric, `all` for the whole network.
+    pub connection_type: String,
+    pub metric: String,
+    pub kind: MetricKind,
+    /// Value predicted by the trend.
+    pub expected: f64,
+    pub actual: f64,
+}
+
+impl fmt::Display for Deviation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let direction = if self.actual < self.expected {
+            "below"
+        } else {
+            "above"
+        };
+        match self.kind {
+            MetricKind::Nodes => write!(
+                f,
+                "{} {} at {:.0} is {:.1}% {direction} the trend of {:.0}",
+                self.connection_type,
+                self.metric,
+                self.actual,
+                ((self.actual - self.expected) / self.expected * 100.0).abs(),
+                self.expected
+            ),
+            MetricKind::Share => write!(
+                f,
+                "{} {} at {:.1}% is {:.1} pp {direction} the trend of {:.1}%",
+                self.connection_type,
+                self.metric,
+                self.actual,
+                (self.actual - self.expected).abs(),
+                self.expected
+            ),
+        }
+    }
+}
+
+/// Check of the latest report against the trend of the reports before it.
+#[derive(Debug, Clone)]
+pub struct TrendCheck {
+    /// Number of reports before the latest the trend is fitted to, at least one.
+    pub window: usize,
+    /// Metrics whose share of listening nodes is checked, in addition to the node counts.
+    pub metrics: Vec<String>,
+    /// Largest allowed deviation of a share, in percentage points.
+    pub share_threshold: f64,
+    /// Largest allowed deviation of a node count, in percent of the trend.
+    pub nodes_threshold: f64,
+    /// Connection types with fewer nodes by trend are not checked, they are too noisy. Must be at
+    /// least one, a trend of no nodes has no relative change.
+    pub min_nodes: usize,
+}
+
+/// Value of a metric predicted at a time by a least squares line through the points.
+///
+/// The mean is used when the points do not span any time.
+fn predict(points: &[(f64, f64)], at: f64) -> f64 {
+    let n = points.len() as f64;
+    let mean_x = points.iter().map(|(x, _)| x).sum::<f64>() / n;
+    let mean_y = points.iter().map(|(_, y)| y).sum::<f64>() / n;
+    let variance: f64 = points.iter().map(|(x, _)| (x - mean_x).powi(2)).sum();
+    if variance == 0.0 {
+        return mean_y;
+    }
+
+    let covariance: f64 = points
+        .iter()
+        .map(|(x, y)| (x - mean_x) * (y - mean_y))
+        .sum();
+    mean_y + covariance / variance * (at - mean_x)
+}
+
+impl TrendCheck {
+    /// Check the latest complete report of a history against the trend of its network's
+    /// complete reports before it, returning the metrics beyond their threshold.
+    ///
+    /// Partial reports and reports of interrupted crawls are skipped, their counts fall short of
+    /// the network. Nothing is checked without at least one report before the latest. Errors if a metric is
+    /// not a feature, service flag or combination counted by every checked report.
+    pub fn check(&self, reports: &[CensusReport]) -> Result<Vec<Deviation>, Box<dyn Error>> {
+        if self.window == 0 {
+            return Err("the trend window needs at least one report".into());
+        }
+        let mut complete: Vec<&CensusReport> = reports
+            .iter()
+            .filter(|report| is_complete(report))
+            .collect();
+        complete.sort_by_key(|report| report.timestamp);
+        let Some(latest) = complete.pop() else {
+            return Ok(Vec::new());
+        };
+        complete.retain(|report| report.network == latest.network);
+        let previous = &complete[complete.len().saturating_sub(self.window)..];
+        if previous.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        if let Some(metric) = self
+            .metrics
+            .iter()
+            .find(|metric| latest.stats.count(metric).is_none())
+        {
+            return Err(format!(
+                "unknown metric {metric}, expected a feature, service flag or combination"
+            )
+            .into());
+        }
+
+        let mut deviations = Vec::new();
+        for network in std::iter::once(None).chain(NetworkType::ALL.map(Some)) {
+            let name = network.map_or_else(|| "all".to_string(), |network| network.to_string());
+            let report_value = |report: &CensusReport, metric: &str, kind: MetricKind| {
+                value(Scope::new(&report.stats, network), metric, kind).ok_or_else(|| {
+                    format!(
+                        "metric {metric} is not counted by the report from {}",
+                        report.timestamp
+                    )
+                })
+            };
+            let series = |metric: &str, kind: MetricKind| -> Result<(f64, f64), String> {
+                let points = previous
+                    .iter()
+                    .map(|report| {
+                        Ok((report.timestamp as f64, report_value(report, metric, kind)?))
+                    })
+                    .collect::<Result<Vec<_>, String>>()?;
+                let expected = predict(&points, latest.timestamp as f64);
+                Ok((expected, report_value(latest, metric, kind)?))
+            };
+
+            let (expected_nodes, actual_nodes) = series("total_nodes", MetricKind::Nodes)?;
+            if expected_nodes < self.min_nodes as f64 {
+                continue;
+            }
+            if ((actual_nodes - expected_nodes) / expected_nodes * 100.0).abs()
+                > self.nodes_threshold
+            {
+                deviations.push(Deviation {
+                    connection_type: name.clone(),
+                    metric: "total_nodes".to_string(),
+                    kind: MetricKind::Nodes,
+                    expected: expected_nodes,
+                    actual: actual_nodes,
+                });
+            }
+
+            for metric in &self.metrics {
+                let (expected, actual) = series(metric, MetricKind::Share)?;
+                if (actual - expected).abs() > self.share_threshold {
+                    deviations.push(Deviation {
+                        connection_type: name.clone(),
+                        metric: metric.clone(),
+                        kind: MetricKind::Share,
+                        expected,
+                        actual,
+                    });
+                }
+            }
+        }
+
+        Ok(deviations)
+    }
+}
+
+/// Whether a report counted the whole network.
+fn is_complete(report: &CensusReport) -> bool {
+    !report.partial && report.stop_reason != Some(StopReason::Interrupted)
+}
+
+/// Value of a metric in a scope, none if the report does not count it.
+fn value(scope: Scope, metric: &str, kind: MetricKind) -> Option<f64> {
+    let count = scope.count(metric)?;
+    Some(match kind {
+        MetricKind::Nodes => count as f64,
+        MetricKind::Share => scope.percentage(count),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::tests::report;
+
+    /// Report with a number of nodes, of which some support v2 transport.
+    fn v2_report(timestamp: u64, total_nodes: usize, v2_transport: usize) -> CensusReport {
+        let mut report = report(timestamp, total_nodes);
+        report.stats.v2_transport = v2_transport;
+        report
+    }
+
+    fn trend_check() -> TrendCheck {
+        TrendCheck {
+            window: 8,
+            metrics: vec!["v2_transport".to_string()],
+            share_threshold: 5.0,
+            nodes_threshold: 20.0,
+            min_nodes: 10,
+        }
+    }
+
+    #[test]
+    fn predict_line() {
+        let points = [(0.0, 0.0), (1.0, 2.0), (2.0, 4.0)];
+        assert_eq!(predict(&points, 3.0), 6.0);
+        assert_eq!(predict(&[(1.0, 2.0), (1.0, 4.0)], 5.0), 3.0);
+    }
+
+    #[test]
+    fn within_trend() {
+        let reports: Vec<CensusReport> = (0..5)
+            .map(|day| v2_report(day, 100 + day as usize * 10, 10 + day as usize * 5))
+            .collect();
+        assert!(trend_check().check(&reports).unwrap().is_empty());
+    }
+
+    #[test]
+    fn deviations() {
+        let mut reports: Vec<CensusReport> = (0..4).map(|day| v2_report(day, 100, 10)).collect();
+        reports.push(v2_report(4, 50, 25));
+        let deviations = trend_check().check(&reports).unwrap();
+        let metrics: Vec<(&str, MetricKind)> = deviations
+            .iter()
+            .map(|deviation| (deviation.metric.as_str(), deviation.kind))
+            .collect();
+        assert_eq!(
+            metrics,
+            vec![
+                ("total_nodes", MetricKind::Nodes),
+                ("v2_transport", MetricKind::Share)
+            ]
+        );
+        assert_eq!(deviations[1].expected, 10.0);
+        assert_eq!(deviations[1].actual, 50.0);
+    }
+
+    #[test]
+    fn incomplete_and_single_reports() {
+        let mut partial = v2_report(5, 1, 0);
+        partial.partial = true;
+        let mut interrupted = v2_report(6, 10, 0);
+        interrupted.stop_reason = Some(StopReason::Interrupted);
+        let reports = vec![
+            v2_report(0, 100, 10),
+            v2_report(4, 100, 10),
+            partial,
+            interrupted,
+        ];
+        assert!(trend_check().check(&reports).unwrap().is_empty());
+        assert!(trend_check().check(&reports[..1]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn unknown_metric() {
+        let reports = vec![v2_report(0, 100, 10), v2_report(1, 100, 10)];
+        let check = TrendCheck {
+            metrics: vec!["v2_transprot".to_string()],
+            ..trend_check()
+        };
+        assert!(check.check(&reports).is_err());
+    }
+
+    #[test]
+    fn empty_window() {
+        let reports = vec![v2_report(0, 100, 10), v2_report(1, 50, 10)];
+        let check = TrendCheck {
+            window: 0,
+            ..trend_check()
+        };
+        assert!(check.check(&reports).is_err());
+    }
+}